@@ -20,8 +20,17 @@ export type Scalars = {
 
 export type Authenticator = {
   __typename?: 'Authenticator';
+  backupEligible: Scalars['Boolean']['output'];
+  backupState: Scalars['Boolean']['output'];
+  counter: Scalars['Int']['output'];
   createdAt: Scalars['DateTime']['output'];
+  discoverable?: Maybe<Scalars['Boolean']['output']>;
+  nickname?: Maybe<Scalars['String']['output']>;
   passkey: Scalars['JSON']['output'];
+  transports: Array<Scalars['String']['output']>;
+  uaBrowser?: Maybe<Scalars['String']['output']>;
+  uaDevice?: Maybe<Scalars['String']['output']>;
+  uaOs?: Maybe<Scalars['String']['output']>;
   userAgentShort: Scalars['String']['output'];
   userId: Scalars['UUID']['output'];
 };
@@ -30,6 +39,7 @@ export type Query = {
   __typename?: 'Query';
   hello: Scalars['String']['output'];
   me?: Maybe<User>;
+  sessionExpiresInSeconds?: Maybe<Scalars['Int']['output']>;
 };
 
 export type User = {
@@ -43,7 +53,7 @@ export type User = {
 export type MeQueryQueryVariables = Exact<{ [key: string]: never; }>;
 
 
-export type MeQueryQuery = { __typename?: 'Query', me?: { __typename?: 'User', id: any, username: string, authenticators: Array<{ __typename?: 'Authenticator', userAgentShort: string, createdAt: any, passkey: any }> } | null };
+export type MeQueryQuery = { __typename?: 'Query', me?: { __typename?: 'User', id: any, username: string, authenticators: Array<{ __typename?: 'Authenticator', userAgentShort: string, createdAt: any, transports: Array<string>, passkey: any }> } | null };
 
 
-export const MeQueryDocument = {"kind":"Document","definitions":[{"kind":"OperationDefinition","operation":"query","name":{"kind":"Name","value":"MeQuery"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"me"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"id"}},{"kind":"Field","name":{"kind":"Name","value":"username"}},{"kind":"Field","name":{"kind":"Name","value":"authenticators"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"userAgentShort"}},{"kind":"Field","name":{"kind":"Name","value":"createdAt"}},{"kind":"Field","name":{"kind":"Name","value":"passkey"}}]}}]}}]}}]} as unknown as DocumentNode<MeQueryQuery, MeQueryQueryVariables>;
\ No newline at end of file
+export const MeQueryDocument = {"kind":"Document","definitions":[{"kind":"OperationDefinition","operation":"query","name":{"kind":"Name","value":"MeQuery"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"me"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"id"}},{"kind":"Field","name":{"kind":"Name","value":"username"}},{"kind":"Field","name":{"kind":"Name","value":"authenticators"},"selectionSet":{"kind":"SelectionSet","selections":[{"kind":"Field","name":{"kind":"Name","value":"userAgentShort"}},{"kind":"Field","name":{"kind":"Name","value":"createdAt"}},{"kind":"Field","name":{"kind":"Name","value":"transports"}},{"kind":"Field","name":{"kind":"Name","value":"passkey"}}]}}]}}]}}]} as unknown as DocumentNode<MeQueryQuery, MeQueryQueryVariables>;
\ No newline at end of file
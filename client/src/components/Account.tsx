@@ -8,6 +8,7 @@ gql`
       authenticators {
         userAgentShort
         createdAt
+        transports
         passkey
       }
     }
@@ -87,6 +88,9 @@ export default function Account() {
               </p>
               <div class="text-sm text-muted-foreground">
                 <p>{authenticator.userAgentShort}</p>
+                <Show when={authenticator.transports.length > 0}>
+                  <p>{authenticator.transports.join(", ")}</p>
+                </Show>
                 <p>
                   {toLocaleRelativeTimeString(
                     new Date(),
@@ -49,8 +49,13 @@ let refreshTimeout: any;
 // if no activity happens, the client will reactively "sign out" on expiry
 function setMeFromCookie() {
   const { me_from_cookie, expiry_date } = get_me_from_cookie();
-  if (me_from_cookie) {
-    setMe(me_from_cookie);
+  if (me_from_cookie || expiry_date) {
+    if (me_from_cookie) {
+      setMe(me_from_cookie);
+    } else if (!me()) {
+      // INFO_COOKIE_CONTENTS=expiry_only: the cookie has no user
+      setMeFromServer();
+    }
     clearTimeout(refreshTimeout);
     refreshTimeout = setTimeout(
       setMeFromCookie,
@@ -61,6 +66,13 @@ function setMeFromCookie() {
   }
 }
 
+async function setMeFromServer() {
+  const res = await fetch("/me");
+  if (res.ok) {
+    setMe(await res.json());
+  }
+}
+
 export const AuthProvider = (props: any) => {
   setMeFromCookie();
   const authContext = {
@@ -3,7 +3,12 @@ type AuthForm = {
 };
 
 import type { SubmitHandler } from "@modular-forms/solid";
-import { createForm, required, minLength } from "@modular-forms/solid";
+import {
+  createForm,
+  getValue,
+  required,
+  minLength,
+} from "@modular-forms/solid";
 import { Button } from "~/components/ui/button";
 import { Grid } from "~/components/ui/grid";
 import { Input } from "~/components/ui/input";
@@ -69,19 +74,37 @@ function UserAuthForm() {
     );
   };
 
-  const handleClickSignIn: (e: Event) => void = (e) => {
+  // credentials which aren't discoverable ("no passkeys found") are only
+  // found with the username, offered after a failed sign in
+  const [showUsernameFallback, setShowUsernameFallback] = createSignal(false);
+
+  const signInWith = (username?: string) => {
     setRegistrationError(null);
     setAuthenticationError(null);
-    authenticate()
+    authenticate(username)
       .then((user) => {
         signIn(user);
       })
       .catch((error) => {
         setAuthenticationError(error.message);
+        setShowUsernameFallback(true);
         throw error;
       });
   };
 
+  const handleClickSignIn: (e: Event) => void = (e) => {
+    signInWith();
+  };
+
+  const handleClickSignInWithUsername: (e: Event) => void = (e) => {
+    const username = getValue(authForm, "username");
+    if (!username) {
+      setAuthenticationError("Enter your username above.");
+      return;
+    }
+    signInWith(username);
+  };
+
   return (
     <div class="grid gap-6">
       <Form onSubmit={handleSubmitRegister}>
@@ -134,6 +157,12 @@ function UserAuthForm() {
       />
 
       {authenticationError() && <GenericError error={authenticationError()} />}
+
+      {showUsernameFallback() && (
+        <Button variant="link" type="button" onClick={handleClickSignInWithUsername}>
+          Key not found? Login with the username above
+        </Button>
+      )}
     </div>
   );
 }
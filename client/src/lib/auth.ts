@@ -37,13 +37,17 @@ export type User = {
 
 export async function register({
   username,
+  nickname,
 }: {
   username: string;
+  // name of the new credential, the server shows the user agent if absent
+  nickname?: string;
 }): Promise<User> {
   ensure_credentials_support();
 
   // get challenge from server
-  const creationChallengeResponse = await fetch(`/register_start/${username}`, {
+  const query = nickname ? `?nickname=${encodeURIComponent(nickname)}` : "";
+  const creationChallengeResponse = await fetch(`/register_start/${username}${query}`, {
     method: "POST",
   }).then(async (res) => {
     if (!res.ok) {
@@ -108,15 +112,21 @@ export async function register({
   return user;
 }
 
-export async function authenticate() {
+// without username: discoverable credentials (passkeys)
+// with username: fallback for credentials which aren't discoverable
+export async function authenticate(username?: string) {
   ensure_credentials_support();
 
+  const [startPath, finishPath] = username
+    ? [`/authenticate_start_for/${username}`, `/authenticate_finish_for`]
+    : [`/authenticate_start`, `/authenticate_finish`];
+
   // get challenge from server
-  const requestChallengeResponse = await fetch(`/authenticate_start`, {
+  const requestChallengeResponse = await fetch(startPath, {
     method: "POST",
   }).then(async (res) => {
     if (!res.ok) {
-      throw new Error(await getErrorMessage(res, "authenticate_start"));
+      throw new Error(await getErrorMessage(res, startPath));
     }
     return res.json() as Promise<any>;
   });
@@ -141,7 +151,7 @@ export async function authenticate() {
   const authCredential = _authCredential as PublicKeyCredential;
 
   // send credential to server
-  const authResult = await fetch(`/authenticate_finish`, {
+  const authResult = await fetch(finishPath, {
     method: "POST",
     body: authenticationPublicKeyCredentialToJSON(authCredential),
     headers: {
@@ -150,7 +160,7 @@ export async function authenticate() {
   });
 
   if (!authResult.ok) {
-    throw new Error(`authenticate_finish failed: ${await authResult.text()}`);
+    throw new Error(`${finishPath} failed: ${await authResult.text()}`);
   }
 
   console.log("Authentication complete");
@@ -46,6 +46,12 @@ export function registrationPublicKeyCredentialToJSON(
         new Uint8Array(credential.response.clientDataJSON),
         true
       ),
+      // @ts-ignore
+      transports: credential.response.getTransports?.(),
+    },
+    // only credProps, the server stores whether the credential is discoverable
+    extensions: {
+      credProps: (credential.getClientExtensionResults() as any).credProps,
     },
     type: credential.type,
   });
@@ -92,11 +98,14 @@ export function authenticationPublicKeyCredentialToJSON(
         new Uint8Array(credential.response.signature),
         true
       ),
-      userHandle: Base64.fromUint8Array(
-        // @ts-ignore
-        new Uint8Array(credential.response.userHandle),
-        true
-      ),
+      // @ts-ignore
+      userHandle: credential.response.userHandle
+        ? Base64.fromUint8Array(
+            // @ts-ignore
+            new Uint8Array(credential.response.userHandle),
+            true
+          )
+        : null,
     },
     type: credential.type,
   });
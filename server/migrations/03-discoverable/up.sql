@@ -0,0 +1,2 @@
+-- credProps.rk reported at registration, null if unknown
+alter table authenticators add column discoverable integer;
@@ -0,0 +1,2 @@
+-- transports reported at registration (json array), null if unknown
+alter table authenticators add column transports text;
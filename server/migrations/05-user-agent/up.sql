@@ -0,0 +1,4 @@
+-- parsed user agent at registration, null if unknown (older rows, no header)
+alter table authenticators add column ua_browser text;
+alter table authenticators add column ua_os text;
+alter table authenticators add column ua_device text;
@@ -0,0 +1,2 @@
+-- chosen by the user at registration, null: shown as user_agent_short
+alter table authenticators add column nickname text;
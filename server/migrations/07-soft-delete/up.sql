@@ -0,0 +1,5 @@
+-- soft deleted users (see delete_user), null: active
+alter table users add column deleted_at text;
+-- usernames of deleted users can be registered again
+drop index idx_users_username_unique;
+create unique index idx_users_username_unique on users(username) where deleted_at is null;
@@ -0,0 +1,10 @@
+-- per user api tokens, see api_token.rs. The secret is only stored as argon2 hash.
+create table tokens (
+  id blob check(length(id) = 16) primary key,
+  user_id blob check(length(user_id) = 16) not null references users(id),
+  hash text not null,
+  name text not null,
+  created_at text not null default (strftime('%Y-%m-%dT%H:%M:%SZ')),
+  last_used_at text,
+  revoked integer not null default 0
+);
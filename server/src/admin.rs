@@ -0,0 +1,202 @@
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequestParts, Json},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{Duration, Utc};
+use std::fs;
+use uuid::Uuid;
+
+use crate::{config::Config, db::DB, error::WebauthnError, queries, session, state::AppState};
+
+/*
+ * admin subcommands, run against the database of DATABASE_URL without
+ * starting the http server. e.g. on fly via `fly ssh console`:
+ *   server --admin list-users
+ *   server --admin delete-user <id>
+ *   server --admin export-authenticators <user id> > user.json
+ *   server --admin import-authenticators user.json
+ *
+ * admin http endpoints (/admin/...) are gated by ExtractAdmin, e.g.
+ *   curl -H "Authorization: Bearer $ADMIN_TOKEN" https://<host>/admin/stats
+ */
+
+const USAGE: &str = "usage: server --admin <list-users | delete-user <id> | \
+    export-authenticators <user id> | import-authenticators <file>>";
+
+pub async fn run(
+    db: &DB,
+    config: &Config,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["list-users"] => {
+            let users = db
+                .background_call("get_all_users", |conn| {
+                    queries::get_all_users(conn).map_err(|e| e.into())
+                })
+                .await?;
+            for user in &users {
+                println!("{}\t{}\t{}", user.id, user.username, user.created_at);
+            }
+            println!("{} users", users.len());
+        }
+        ["delete-user", id] => {
+            let id = Uuid::parse_str(id)?;
+            let hard = config.hard_delete;
+            let user_key = config.session_key(session::AUTHENTICATED_USER);
+            let (deleted, sessions) = db
+                .timed_call_with_timeout("delete_user", db.admin_call_timeout, move |conn| {
+                    let deleted = queries::delete_user(conn, id, hard)?;
+                    // signed out (soft or hard), not only once they expire
+                    let sessions = queries::delete_sessions_of_user(conn, id, &user_key)?;
+                    Ok((deleted, sessions))
+                })
+                .await?;
+            if deleted == 0 {
+                return Err(format!("user {id} not found").into());
+            }
+            let how = if hard { "deleted" } else { "soft deleted" };
+            println!("{how} user {id}, signed out {sessions} sessions");
+        }
+        // passkeys are bound to the RP_ID, they only work on instances with
+        // the same RP_ID (e.g. staging and prod of the same domain)
+        ["export-authenticators", id] => {
+            let id = Uuid::parse_str(id)?;
+            let (user, authenticators) = db
+                .background_call("export_authenticators", move |conn| {
+                    let user = queries::get_user_by_id(conn, id)?;
+                    let authenticators = queries::export_authenticators(conn, id)?;
+                    Ok((user, authenticators))
+                })
+                .await?;
+            eprintln!(
+                "exported {} authenticators of {} (RP_ID {}), only importable with the same RP_ID!",
+                authenticators.len(),
+                user.username,
+                config.rp_id
+            );
+            let export = queries::AuthenticatorsExport {
+                rp_id: config.rp_id.clone(),
+                user,
+                authenticators,
+            };
+            println!("{}", serde_json::to_string_pretty(&export)?);
+        }
+        ["import-authenticators", file] => {
+            let export: queries::AuthenticatorsExport =
+                serde_json::from_str(&fs::read_to_string(file)?)?;
+            if export.rp_id != config.rp_id {
+                return Err(format!(
+                    "RP_ID mismatch: exported with {}, this instance is {}. \
+                    The passkeys wouldn't work here, not importing.",
+                    export.rp_id, config.rp_id
+                )
+                .into());
+            }
+            let username = export.user.username.clone();
+            let imported = db
+                .timed_call_with_timeout(
+                    "import_authenticators",
+                    db.admin_call_timeout,
+                    move |conn| {
+                        queries::import_authenticators(conn, export.user, &export.authenticators)
+                            .map_err(|e| e.into())
+                    },
+                )
+                .await?;
+            println!("imported {imported} authenticators of {username}");
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+// `Authorization: Bearer <ADMIN_TOKEN>` of operators, not a signed in user.
+// Without ADMIN_TOKEN the admin endpoints don't exist (404).
+pub struct ExtractAdmin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ExtractAdmin
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let admin_token = parts
+            .extensions
+            .get::<AppState>()
+            .and_then(|app_state| app_state.config.admin_token.clone())
+            .ok_or((StatusCode::NOT_FOUND, "Not found"))?;
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or("");
+        if !constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+            warn!("Admin request with invalid token");
+            return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
+        }
+        Ok(ExtractAdmin)
+    }
+}
+
+// doesn't leak the matching prefix length through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(serde::Serialize)]
+pub struct Stats {
+    user_count: i64,
+    authenticator_count: i64,
+    // not expired, including sessions which aren't signed in
+    active_session_count: i64,
+    // online in the chat, null without the chat feature
+    connected_chat_users: Option<usize>,
+    registrations_last_24h: i64,
+}
+
+// operator overview, the counts of one db call plus the in-memory chat state
+pub async fn get_stats(
+    _admin: ExtractAdmin,
+    Extension(app_state): Extension<AppState>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let since = Utc::now() - Duration::hours(24);
+    let (user_count, authenticator_count, active_session_count, registrations_last_24h) = app_state
+        .db
+        .timed_call_with_timeout("get_stats", app_state.db.admin_call_timeout, move |conn| {
+            Ok((
+                queries::count_users(conn)?,
+                queries::count_authenticators(conn)?,
+                queries::count_active_sessions(conn)?,
+                queries::count_registrations_since(conn, since)?,
+            ))
+        })
+        .await
+        .map_err(|e| {
+            error!("get_stats: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+
+    Ok(Json(Stats {
+        user_count,
+        authenticator_count,
+        active_session_count,
+        connected_chat_users: connected_chat_users(&app_state),
+        registrations_last_24h,
+    }))
+}
+
+#[cfg(feature = "chat")]
+fn connected_chat_users(app_state: &AppState) -> Option<usize> {
+    Some(app_state.connected_usernames.lock().unwrap().len())
+}
+
+#[cfg(not(feature = "chat"))]
+fn connected_chat_users(_app_state: &AppState) -> Option<usize> {
+    None
+}
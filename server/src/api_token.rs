@@ -0,0 +1,243 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    error::WebauthnError,
+    models::{ApiToken, User},
+    queries, random,
+    reauth::ExtractReauth,
+    session::ExtractMeEnsure,
+    state::AppState,
+};
+
+/*
+ * per user api tokens, `Authorization: Bearer <token>` acts as the user on
+ * /me, /me/authenticators and /graphql (see ExtractMeOrToken).
+ * token: pat_<id>_<secret>, the id (uuid, simple) locates the row, only the
+ * argon2 hash of the secret is stored. The secret is shown once at creation.
+ * argon2 costs some ms per request, fine for programmatic access.
+ */
+
+pub const TOKEN_PREFIX: &str = "pat_";
+
+// bytes of randomness of the secret, hex encoded
+const SECRET_LEN: usize = 32;
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub struct CreateTokenParams {
+    name: String,
+}
+
+// the token, plus the secret of this response only
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct CreateTokenResponse {
+    #[serde(flatten)]
+    token: ApiToken,
+    // pat_<id>_<secret>, can't be shown again
+    secret: String,
+}
+
+// only with a session, tokens can't mint tokens. And a fresh reauth, a
+// hijacked session mustn't turn into a long lived token.
+#[utoipa::path(post, path = "/me/tokens",
+    request_body = CreateTokenParams,
+    params(("X-Reauth-Token" = String, Header, description = "from /reauth_finish")),
+    responses(
+        (status = 201, body = CreateTokenResponse),
+        (status = 400, description = "Invalid name"),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "Missing or expired reauth token"),
+    )
+)]
+pub async fn create_token(
+    Extension(app_state): Extension<AppState>,
+    ExtractReauth(me): ExtractReauth,
+    Json(params): Json<CreateTokenParams>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let name = params.name.trim().to_string();
+    let len = name.chars().count();
+    if !(1..=64).contains(&len) || name.chars().any(char::is_control) {
+        return Err(WebauthnError::InvalidTokenName);
+    }
+
+    let token = ApiToken {
+        id: Uuid::now_v7(),
+        name,
+        created_at: Utc::now(),
+        last_used_at: None,
+        revoked: false,
+    };
+    let secret_bytes: [u8; SECRET_LEN] = random::random_bytes();
+    let secret: String = secret_bytes.iter().map(|b| format!("{b:02x}")).collect();
+
+    let hash = tokio::task::spawn_blocking({
+        let secret = secret.clone();
+        move || hash_secret(&secret)
+    })
+    .await
+    .map_err(|e| {
+        error!("hash_secret: {:?}", e);
+        WebauthnError::Unknown
+    })??;
+
+    app_state
+        .db
+        .timed_call("insert_api_token", {
+            let token = token.clone();
+            let user_id = me.id;
+            move |conn| queries::insert_api_token(conn, user_id, &token, hash).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("insert_api_token: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+
+    info!("User {} created api token {}", me.id, token.id);
+    let secret = format!("{TOKEN_PREFIX}{}_{secret}", token.id.simple());
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateTokenResponse { token, secret }),
+    ))
+}
+
+// including revoked ones, for the account page
+#[utoipa::path(get, path = "/me/tokens", responses(
+    (status = 200, body = Vec<ApiToken>),
+    (status = 401, description = "Not signed in"),
+))]
+pub async fn get_my_tokens(
+    Extension(app_state): Extension<AppState>,
+    ExtractMeEnsure(me): ExtractMeEnsure,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let tokens = app_state
+        .db
+        .timed_call("get_api_tokens_for_user_id", move |conn| {
+            queries::get_api_tokens_for_user_id(conn, me.id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_api_tokens_for_user_id: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+    Ok(Json(tokens))
+}
+
+// revoked tokens stay listed, idempotent
+#[utoipa::path(delete, path = "/me/tokens/{id}",
+    params(("id" = Uuid, Path, description = "id of the token")),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 401, description = "Not signed in"),
+        (status = 404, description = "Not a token of the user"),
+    )
+)]
+pub async fn revoke_token(
+    Extension(app_state): Extension<AppState>,
+    ExtractMeEnsure(me): ExtractMeEnsure,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let revoked = app_state
+        .db
+        .timed_call("revoke_api_token", move |conn| {
+            queries::revoke_api_token(conn, me.id, id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("revoke_api_token: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+    if revoked == 0 {
+        return Err(WebauthnError::TokenNotFound);
+    }
+    info!("User {} revoked api token {id}", me.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// the user of a pat_ token, None if it's malformed, unknown, revoked or the
+// secret doesn't match. Err only for db (and hashing) failures.
+pub async fn user_for_token(
+    app_state: &AppState,
+    token: &str,
+) -> Result<Option<User>, WebauthnError> {
+    let Some((id, secret)) = token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|t| t.split_once('_'))
+    else {
+        return Ok(None);
+    };
+    let Ok(id) = Uuid::parse_str(id) else {
+        return Ok(None);
+    };
+
+    let found = app_state
+        .db
+        .timed_call("get_api_token_user_and_hash", move |conn| {
+            queries::get_api_token_user_and_hash(conn, id).map_err(|e| e.into())
+        })
+        .await;
+    let (user, hash) = match found {
+        Ok(found) => found,
+        Err(tokio_rusqlite::Error::Rusqlite(rusqlite::Error::QueryReturnedNoRows)) => {
+            return Ok(None)
+        }
+        Err(e) => {
+            error!("get_api_token_user_and_hash: {:?}", e);
+            return Err(WebauthnError::from_db(&e));
+        }
+    };
+
+    let secret = secret.to_string();
+    let valid = tokio::task::spawn_blocking(move || verify_secret(&secret, &hash))
+        .await
+        .map_err(|e| {
+            error!("verify_secret: {:?}", e);
+            WebauthnError::Unknown
+        })?;
+    if !valid {
+        return Ok(None);
+    }
+
+    // not worth failing the request for
+    if let Err(e) = app_state
+        .db
+        .timed_call("touch_api_token", move |conn| {
+            queries::touch_api_token(conn, id).map_err(|e| e.into())
+        })
+        .await
+    {
+        warn!("touch_api_token: {:?}", e);
+    }
+    Ok(Some(user))
+}
+
+// phc string (algorithm, params and salt included), argon2id defaults
+fn hash_secret(secret: &str) -> Result<String, WebauthnError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| {
+            error!("Failed to hash api token: {:?}", e);
+            WebauthnError::Unknown
+        })
+}
+
+fn verify_secret(secret: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(secret.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
@@ -1,19 +1,29 @@
+use crate::client_ip::ClientIp;
+use crate::config::Config;
+use crate::error::WebauthnError;
+use crate::events::UserEvent;
 use crate::models::User;
-use crate::session::ExtractMe;
+use crate::payload::Payload;
+use crate::session::{
+    ExtractMe, ExtractMeEnsure, AUTH_FOR_STATE, AUTH_STATE, MERGE_STATE, POW_STATE, REAUTH_STATE,
+    REG_FINISHED, REG_STATE,
+};
 use crate::state::AppState;
-use crate::{error::WebauthnError, ua::user_agent::get_user_agent_string_short};
-use crate::{queries, session};
+use crate::{queries, random, reauth, session};
 use axum::{
-    extract::{Extension, Json, Path},
+    extract::{Extension, Json, Path, Query},
+    http::HeaderMap,
     response::IntoResponse,
 };
 use chrono::Utc;
+use sha2::{Digest, Sha256};
 use tower_cookies::Cookies;
 use tower_sessions::Session;
+use unicode_normalization::UnicodeNormalization;
 
 use webauthn_rs::prelude::*;
 
-use crate::ua::user_agent::ExtractUserAgent;
+use crate::ua::user_agent::{get_user_agent_string_short, ExtractUserAgent, ParsedUserAgent};
 
 // Webauthn RS auth handlers.
 // adapted for "conditional-ui" (no username required for authentication) based on the example here:
@@ -51,20 +61,170 @@ use crate::ua::user_agent::ExtractUserAgent;
 //                  │                     │                      │
 //
 
+// length and allowed characters of usernames
+// default: ascii letters, digits, _, - and .
+// USERNAME_ALLOW_UNICODE=true: letters and digits of all scripts, still no
+// whitespace, control, format (e.g. zero width) characters or emoji.
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 24;
+fn validate_username(username: &str, config: &Config) -> Result<(), WebauthnError> {
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        return Err(WebauthnError::InvalidUsername);
+    }
+    let is_allowed = |c: char| {
+        let is_letter_or_digit = if config.username_allow_unicode {
+            c.is_alphanumeric()
+        } else {
+            c.is_ascii_alphanumeric()
+        };
+        is_letter_or_digit || c == '_' || c == '-' || c == '.'
+    };
+    if !username.chars().all(is_allowed) {
+        return Err(WebauthnError::InvalidUsernameCharacters);
+    }
+    Ok(())
+}
+
+// NFC: composed and decomposed accented characters (e.g. "é" and "e\u{301}")
+// look the same, they must be the same username
+fn normalize_username(username: &str) -> String {
+    username.nfc().collect()
+}
+
+// reserved usernames can only be created out of band, e.g. in the db
+// case insensitive, RESERVED_USERNAMES are lowercased in Config::from_env
+fn is_reserved_username(username: &str, config: &Config) -> bool {
+    let normalized = username.trim().to_lowercase();
+    config.reserved_usernames.contains(&normalized)
+}
+
+// optional name of the new credential, e.g. "work laptop", shown instead of
+// user_agent_short. Stored with the reg_state, set by finish_register.
+fn validate_nickname(nickname: Option<String>) -> Result<Option<String>, WebauthnError> {
+    let Some(nickname) = nickname.map(|n| n.trim().to_string()) else {
+        return Ok(None);
+    };
+    let len = nickname.chars().count();
+    if !(1..=64).contains(&len) || nickname.chars().any(char::is_control) {
+        return Err(WebauthnError::InvalidNickname);
+    }
+    Ok(Some(nickname))
+}
+
+// proof of work against automated mass registration (POW_REGISTRATION)
+// register_challenge issues a random prefix, register_start of a new user then
+// requires a nonce (X-Pow-Nonce header) so that sha256(prefix + nonce) starts
+// with POW_DIFFICULTY zero bits. ~2^difficulty hashes for the client, one for
+// the server. The challenge is kept in the session and single use.
+// register_challenge is only routed with POW_REGISTRATION, see api_router.
+const POW_NONCE_HEADER: &str = "x-pow-nonce";
+const POW_CHALLENGE_SECONDS: i64 = 300;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PowChallenge {
+    prefix: String,
+    difficulty: u32,
+    expires_at: i64,
+}
+
+pub async fn register_challenge(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let prefix: [u8; 16] = random::random_bytes();
+    let challenge = PowChallenge {
+        prefix: prefix.iter().map(|b| format!("{b:02x}")).collect(),
+        difficulty: app_state.config.pow_difficulty,
+        expires_at: Utc::now().timestamp() + POW_CHALLENGE_SECONDS,
+    };
+    session
+        .insert(&app_state.config.session_key(POW_STATE), &challenge)
+        .await
+        .map_err(|e| {
+            error!("Failed to insert pow_state into session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+    Ok(Json(challenge))
+}
+
+// the nonce of the request solves the challenge of the session, which is
+// removed either way. Only hashing, no db work.
+async fn verify_proof_of_work(
+    session: &Session,
+    headers: &HeaderMap,
+    config: &Config,
+) -> Result<(), WebauthnError> {
+    let challenge: Option<PowChallenge> = session
+        .remove(&config.session_key(POW_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove pow_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+    let nonce = headers.get(POW_NONCE_HEADER).and_then(|v| v.to_str().ok());
+    let (Some(challenge), Some(nonce)) = (challenge, nonce) else {
+        return Err(WebauthnError::ProofOfWorkRequired);
+    };
+    if challenge.expires_at < Utc::now().timestamp() {
+        return Err(WebauthnError::ProofOfWorkRequired);
+    }
+    let hash = Sha256::digest(format!("{}{nonce}", challenge.prefix));
+    if leading_zero_bits(&hash) < challenge.difficulty {
+        info!("Invalid proof of work");
+        return Err(WebauthnError::ProofOfWorkInvalid);
+    }
+    Ok(())
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RegisterStartParams {
+    nickname: Option<String>,
+}
+
 // respond to the start registration request, provide the challenge to the browser.
+#[utoipa::path(post, path = "/register_start/{username}",
+    params(
+        ("username" = String, Path, description = "new user or the signed in user"),
+        ("nickname" = Option<String>, Query, description = "name of the new credential"),
+    ),
+    responses(
+        (status = 200, description = "CreationChallengeResponse", body = serde_json::Value),
+        (status = 409, description = "Username already exists or reserved", body = String),
+        (status = 500, description = "WebauthnError", body = String),
+    )
+)]
 pub async fn start_register(
     Extension(app_state): Extension<AppState>,
     session: Session,
     Path(username): Path<String>,
-    // error early if user_agent is missing or invalid
+    Query(params): Query<RegisterStartParams>,
+    headers: HeaderMap,
+    // error early if user_agent is missing (and required) or invalid
     ExtractUserAgent(_user_agent): ExtractUserAgent,
     ExtractMe(me): ExtractMe,
 ) -> Result<impl IntoResponse, WebauthnError> {
     info!("Start register");
 
-    // check username
-    if username.len() < 3 || username.len() > 24 {
-        return Err(WebauthnError::InvalidUsername);
+    // check username, stored and compared in NFC, see normalize_username
+    let username = normalize_username(&username);
+    validate_username(&username, &app_state.config)?;
+    let nickname = validate_nickname(params.nickname)?;
+
+    // new accounts only, signed in users adding a credential are known
+    if app_state.config.pow_registration && me.is_none() {
+        verify_proof_of_work(&session, &headers, &app_state.config).await?;
     }
 
     let (user, user_is_new) = match me {
@@ -78,18 +238,21 @@ pub async fn start_register(
     };
 
     if user_is_new {
+        if is_reserved_username(&username, &app_state.config) {
+            return Err(WebauthnError::UsernameReserved);
+        }
+
         // check if username exists
         if app_state
             .db
-            .conn
-            .call({
+            .timed_call("check_username_exists", {
                 let username = user.username.clone();
                 move |conn| queries::check_username_exists(conn, &username).map_err(|e| e.into())
             })
             .await
             .map_err(|e| {
                 error!("check_username_exists: {:?}", e);
-                WebauthnError::GenericDatabaseError
+                WebauthnError::from_db(&e)
             })?
         {
             return Err(WebauthnError::UsernameAlreadyExists);
@@ -102,15 +265,17 @@ pub async fn start_register(
     } else {
         let authenticators = app_state
             .db
-            .conn
-            .call(move |conn| {
+            .timed_call("get_authenticators_for_user_id", move |conn| {
                 queries::get_authenticators_for_user_id(conn, user.id).map_err(|e| e.into())
             })
             .await
             .map_err(|e| {
                 error!("get_authenticators_for_user: {:?}", e);
-                WebauthnError::GenericDatabaseError
+                WebauthnError::from_db(&e)
             })?;
+        if authenticators.len() >= app_state.config.max_authenticators_per_user {
+            return Err(WebauthnError::TooManyAuthenticators);
+        }
         Some(
             authenticators
                 .iter()
@@ -120,10 +285,15 @@ pub async fn start_register(
     };
 
     // Remove any previous registrations that may have occured from the session.
-    session.remove_value("reg_state").await.map_err(|e| {
-        error!("Failed to remove reg_state from session: {:?}", e);
-        WebauthnError::CorruptSession
-    })?;
+    // If it was started in another tab, finishing it there fails with
+    // RegistrationReplaced, see finish_register.
+    session
+        .remove_value(&app_state.config.session_key(REG_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove reg_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
 
     let res = match app_state.webauthn.start_passkey_registration(
         user.id,
@@ -131,11 +301,24 @@ pub async fn start_register(
         &user.username,
         exclude_credentials,
     ) {
-        Ok((ccr, reg_state)) => {
+        Ok((mut ccr, reg_state)) => {
+            // request credProps: tells whether the credential is discoverable
+            // (resident), see finish_register
+            if let Some(extensions) = ccr.public_key.extensions.as_mut() {
+                extensions.cred_props = Some(true);
+            }
+            // only offer the algorithms of WEBAUTHN_ALGORITHMS, checked again in finish_register
+            let algorithms = &app_state.config.webauthn_algorithms;
+            ccr.public_key
+                .pub_key_cred_params
+                .retain(|p| algorithms.iter().any(|alg| *alg as i64 == p.alg));
             // Store auth state in session. This is only save because session
             // store is server side. A cookie store would enable replay attacks.
             session
-                .insert("reg_state", (user, user_is_new, reg_state))
+                .insert(
+                    &app_state.config.session_key(REG_STATE),
+                    (user, user_is_new, reg_state, nickname),
+                )
                 .await
                 .map_err(|e| {
                     error!("Failed to insert reg_state into session: {:?}", e);
@@ -152,87 +335,352 @@ pub async fn start_register(
     Ok(res)
 }
 
+// the user, plus whether the new credential is discoverable (credProps.rk)
+// false: the authenticator created a non-resident credential, sign in
+// without username (start_authentication) won't find it. null: unknown.
+// pending_additional: the user was just created and the session is signed in
+// as them, so an additional credential (e.g. a backup key) can be registered
+// right away with register_start/:username, without authenticating first.
+// weak_algorithm: the credential isn't of the preferred algorithm (the first
+// of WEBAUTHN_ALGORITHMS), the client can suggest another authenticator.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct RegisterFinishResponse {
+    #[serde(flatten)]
+    user: User,
+    credential_discoverable: Option<bool>,
+    pending_additional: bool,
+    weak_algorithm: bool,
+}
+
+// allowed, but not the first of WEBAUTHN_ALGORITHMS
+fn is_weak_algorithm(algorithm: COSEAlgorithm, config: &Config) -> bool {
+    config.webauthn_algorithms.first() != Some(&algorithm)
+}
+
 // The browser has completed navigator.credentials.create and created a public key
 // on their device. Verify the registration options and persist them.
+#[utoipa::path(post, path = "/register_finish",
+    request_body(
+        description = "RegisterPublicKeyCredential, json, cbor or msgpack",
+        content = serde_json::Value
+    ),
+    responses(
+        (status = 200, body = RegisterFinishResponse),
+        (status = 500, description = "WebauthnError", body = String),
+    )
+)]
 pub async fn finish_register(
     Extension(app_state): Extension<AppState>,
     session: Session,
     cookies: Cookies,
     ExtractUserAgent(user_agent): ExtractUserAgent,
     ExtractMe(me): ExtractMe,
-    Json(reg): Json<RegisterPublicKeyCredential>,
+    Payload(reg): Payload<RegisterPublicKeyCredential>,
 ) -> Result<impl IntoResponse, WebauthnError> {
-    let ua_short = get_user_agent_string_short(&user_agent, &app_state.ua_parser);
+    let user_agent = user_agent.map(|ua| ParsedUserAgent::parse(&ua, &app_state.ua_parser));
+    let ua_short = get_user_agent_string_short(user_agent.as_ref());
 
-    let (user, user_is_new, reg_state): (User, bool, PasskeyRegistration) = session
-        .get("reg_state")
+    let reg_state: Option<(User, bool, PasskeyRegistration, Option<String>)> = session
+        .get(&app_state.config.session_key(REG_STATE))
         .await
         .map_err(|e| {
             error!("Failed to get reg_state from session: {:?}", e);
             WebauthnError::CorruptSession
-        })?
-        .ok_or_else(|| {
-            error!("Failed to get session");
+        })?;
+    let (user, user_is_new, reg_state, nickname) = match reg_state {
+        Some(reg_state) => reg_state,
+        // reg_state is removed by the first call, this may be a double-submit
+        None => {
+            return finish_register_retry(&app_state, &session, me, &reg)
+                .await
+                .map(Json)
+        }
+    };
+
+    let res = app_state
+        .webauthn
+        .finish_passkey_registration(&reg, &reg_state);
+
+    // The challenge doesn't match if the registration was restarted after
+    // this credential was created, e.g. in another tab (possibly for a
+    // different username). Keep the reg_state so the newer one can finish.
+    if let Err(webauthn_rs::prelude::WebauthnError::MismatchedChallenge) = res {
+        info!(
+            "finish_passkey_registration: challenge mismatch, newer registration for {}",
+            user.username
+        );
+        return Err(WebauthnError::RegistrationReplaced);
+    }
+
+    session
+        .remove_value(&app_state.config.session_key(REG_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove reg_state from session: {:?}", e);
             WebauthnError::CorruptSession
         })?;
 
-    session.remove_value("reg_state").await.map_err(|e| {
-        error!("Failed to remove reg_state from session: {:?}", e);
-        WebauthnError::CorruptSession
-    })?;
+    // credProps.rk as reported by the client, None if not reported
+    let discoverable = reg.extensions.cred_props.as_ref().map(|p| p.rk);
+    // transport hints, for the allow credentials of the non-discoverable fallback
+    let transports = reg.response.transports.clone();
 
-    let res = match app_state
-        .webauthn
-        .finish_passkey_registration(&reg, &reg_state)
-    {
+    let res = match res {
         Ok(sk) => {
-            // save user and passkey to db
-            app_state
+            // the client could ignore pub_key_cred_params of start_register
+            let algorithm = sk.get_public_key().type_;
+            if !app_state.config.webauthn_algorithms.contains(&algorithm) {
+                info!("Credential algorithm {:?} not allowed", algorithm);
+                return Err(WebauthnError::AlgorithmNotAllowed);
+            }
+            let weak_algorithm = is_weak_algorithm(algorithm, &app_state.config);
+            if weak_algorithm {
+                warn!(
+                    "Credential of {} uses {:?}, not the preferred {:?}",
+                    user.username, algorithm, app_state.config.webauthn_algorithms[0]
+                );
+            }
+
+            // save user and passkey to db, the limit is checked again as
+            // other registrations may have finished since start_register
+            let max_authenticators = app_state.config.max_authenticators_per_user;
+            let inserted = app_state
                 .db
-                .conn
-                .call({
+                .timed_call_retry("insert_user_and_passkey", {
                     let user = user.clone();
+                    let authenticator = queries::NewAuthenticator {
+                        passkey: sk,
+                        user_agent,
+                        discoverable,
+                        transports,
+                        nickname,
+                    };
                     move |conn| {
                         if user_is_new {
-                            queries::insert_user_and_passkey(conn, user, sk.clone(), &ua_short)
+                            queries::insert_user_and_passkey(conn, user.clone(), &authenticator)
                                 .map_err(|e| e.into())
+                                .map(|_| true)
                         } else {
-                            queries::insert_authenticator(
+                            queries::insert_authenticator_within_limit(
                                 conn,
                                 user.id,
-                                sk.clone(),
                                 Utc::now(),
-                                &ua_short,
+                                &authenticator,
+                                max_authenticators,
                             )
                             .map_err(|e| e.into())
-                            .map(|_| ())
                         }
                     }
                 })
                 .await
                 .map_err(|e| {
                     error!("insert_user_and_passkey: {:?}", e);
-                    WebauthnError::GenericDatabaseError
+                    WebauthnError::from_db(&e)
                 })?;
+            if !inserted {
+                return Err(WebauthnError::TooManyAuthenticators);
+            }
 
             info!("finish register successful!");
 
-            // set session authenticated
+            if !user_is_new {
+                app_state.events.emit(
+                    user.id,
+                    UserEvent::AuthenticatorAdded {
+                        user_agent_short: ua_short,
+                    },
+                );
+            }
+
+            if discoverable == Some(false) {
+                warn!(
+                    "Credential of {} is not discoverable, sign in without username won't find it",
+                    user.username
+                );
+            }
+
+            // the response of a retry of this call, see finish_register_retry
+            session
+                .insert(
+                    &app_state.config.session_key(REG_FINISHED),
+                    (reg.id.clone(), user_is_new),
+                )
+                .await
+                .map_err(|e| {
+                    error!("Failed to insert reg_finished into session: {:?}", e);
+                    WebauthnError::CorruptSession
+                })?;
+
+            // set session authenticated, start_register treats the follow-up
+            // registration of a new user as adding a credential (pending_additional)
             if me.is_none() {
-                session::set_me_authenticated(user.clone(), session, cookies).await?;
+                session::set_me_authenticated(user.clone(), session, cookies, &app_state.config)
+                    .await?;
             }
 
-            Json(user)
+            Json(RegisterFinishResponse {
+                user,
+                credential_discoverable: discoverable,
+                pending_additional: user_is_new,
+                weak_algorithm,
+            })
         }
         Err(e) => {
             error!("finish_passkey_registration: {:?}", e);
-            return Err(WebauthnError::Unknown);
+            return Err(ceremony_error(
+                e,
+                reg.response.client_data_json.as_ref(),
+                &app_state.config,
+            ));
         }
     };
 
     Ok(res)
 }
 
+// credential id of a discoverable assertion. The user handle it also names is
+// ignored, the owner is looked up by the credential, see get_passkey.
+fn identify_passkey_id(
+    app_state: &AppState,
+    auth_input: &PublicKeyCredential,
+) -> Result<String, WebauthnError> {
+    let (_user_handle, cred_id) = app_state
+        .webauthn
+        .identify_discoverable_authentication(auth_input)
+        .map_err(|e| {
+            info!("identify_discoverable_authentication: {:?}", e);
+            WebauthnError::UserAndCredentialDontMatch
+        })?;
+    // make string from &[u8] to be able to copy it and not have lifetime
+    // dependency on auth_input.
+    Ok(Base64UrlSafeData::from(cred_id).to_string())
+}
+
+// the owner and passkey of a credential id of an active user
+// AccountNoLongerExists if not registered (anymore)
+async fn get_passkey(
+    app_state: &AppState,
+    passkey_id: String,
+) -> Result<(Uuid, Passkey), WebauthnError> {
+    app_state
+        .db
+        .timed_call("get_passkey_by_passkey_id", {
+            let passkey_id = passkey_id.clone();
+            move |conn| queries::get_passkey_by_passkey_id(conn, passkey_id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_passkey_by_passkey_id: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?
+        .ok_or_else(|| {
+            info!("Passkey {passkey_id} is not registered (anymore)");
+            WebauthnError::AccountNoLongerExists
+        })
+}
+
+// error of a failed webauthn ceremony (registration or authentication)
+// origin or rp id mismatches are mostly misconfigurations (RP_ORIGIN behind a
+// reverse proxy, another domain) or browser bugs, not attacks. They get
+// OriginMismatch, the expected and received origin are only logged.
+fn ceremony_error(
+    e: webauthn_rs::prelude::WebauthnError,
+    client_data_json: &[u8],
+    config: &Config,
+) -> WebauthnError {
+    use webauthn_rs::prelude::WebauthnError as CoreError;
+    match e {
+        CoreError::InvalidRPOrigin | CoreError::InvalidRPIDHash => {
+            let received = serde_json::from_slice::<serde_json::Value>(client_data_json)
+                .ok()
+                .and_then(|c| c.get("origin")?.as_str().map(str::to_string));
+            warn!(
+                "Origin mismatch ({:?}): expected {} (RP_ID {}), received {:?}",
+                e, config.rp_origin, config.rp_id, received
+            );
+            WebauthnError::OriginMismatch
+        }
+        _ => WebauthnError::Unknown,
+    }
+}
+
+// max age of the credential for a finish_register retry
+const REGISTER_RETRY_WINDOW_SECONDS: i64 = 300;
+
+// A retried finish_register (double-submit, retry on a flaky network) finds
+// no reg_state, the first call removed it. It gets the response of the first
+// call if that succeeded:
+// - the session is signed in (the first call signed it in or it was already)
+// - the first call of this session finished the credential of the request
+//   (REG_FINISHED, with its pending_additional)
+// - the credential id of the request is one of the signed in user's
+//   credentials, and it was registered in the last few minutes
+// A stale or forged request fails one of these and gets CorruptSession, as
+// before. Nothing is verified or stored again, the retry can only return the
+// signed in user's own data.
+async fn finish_register_retry(
+    app_state: &AppState,
+    session: &Session,
+    me: Option<User>,
+    reg: &RegisterPublicKeyCredential,
+) -> Result<RegisterFinishResponse, WebauthnError> {
+    let Some(me) = me else {
+        error!("Failed to get reg_state from session");
+        return Err(WebauthnError::CorruptSession);
+    };
+
+    let finished: Option<(String, bool)> = session
+        .get(&app_state.config.session_key(REG_FINISHED))
+        .await
+        .map_err(|e| {
+            error!("Failed to get reg_finished from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+    let pending_additional = match finished {
+        Some((id, pending_additional)) if id == reg.id => pending_additional,
+        _ => {
+            error!("Failed to get reg_state from session, credential not finished here");
+            return Err(WebauthnError::CorruptSession);
+        }
+    };
+
+    let authenticators = app_state
+        .db
+        .timed_call("get_authenticators_for_user_id", {
+            let user_id = me.id;
+            move |conn| queries::get_authenticators_for_user_id(conn, user_id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_authenticators_for_user: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+
+    let raw_id: &[u8] = reg.raw_id.as_ref();
+    let authenticator = authenticators
+        .iter()
+        .find(|a| AsRef::<[u8]>::as_ref(a.passkey.cred_id()) == raw_id)
+        .filter(|a| (Utc::now() - a.created_at).num_seconds() < REGISTER_RETRY_WINDOW_SECONDS)
+        .ok_or_else(|| {
+            error!("Failed to get reg_state from session, no recent matching credential");
+            WebauthnError::CorruptSession
+        })?;
+
+    info!(
+        "finish register retried by {}, returning the first result",
+        me.username
+    );
+    Ok(RegisterFinishResponse {
+        credential_discoverable: authenticator.discoverable,
+        pending_additional,
+        weak_algorithm: is_weak_algorithm(
+            authenticator.passkey.get_public_key().type_,
+            &app_state.config,
+        ),
+        user: me,
+    })
+}
+
 // The authentication flow:
 //
 //          ┌───────────────┐     ┌───────────────┐      ┌───────────────┐
@@ -263,6 +711,10 @@ pub async fn finish_register(
 // The user indicates the wish to start authentication and we need to provide a challenge.
 // we use start_discoverable_authentication instead of start_passkey_authentication to allow
 // the user to select a key to authenticate with.
+#[utoipa::path(post, path = "/authenticate_start", responses(
+    (status = 200, description = "RequestChallengeResponse", body = serde_json::Value),
+    (status = 500, description = "WebauthnError", body = String),
+))]
 pub async fn start_authentication(
     Extension(app_state): Extension<AppState>,
     session: Session,
@@ -275,17 +727,20 @@ pub async fn start_authentication(
     }
 
     // Remove any previous authentication that may have occured from the session.
-    session.remove_value("auth_state").await.map_err(|e| {
-        error!("Failed to remove auth_state from session: {:?}", e);
-        WebauthnError::CorruptSession
-    })?;
+    session
+        .remove_value(&app_state.config.session_key(AUTH_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove auth_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
 
     let res = match app_state.webauthn.start_discoverable_authentication() {
         Ok((rcr, auth_state)) => {
             // Store auth state in session. This is only save because session
             // store is server side. A cookie store would enable replay attacks.
             session
-                .insert("auth_state", auth_state)
+                .insert(&app_state.config.session_key(AUTH_STATE), auth_state)
                 .await
                 .map_err(|e| {
                     error!("Failed to insert auth_state into session: {:?}", e);
@@ -301,22 +756,75 @@ pub async fn start_authentication(
     Ok(res)
 }
 
+// the credential that signed in, e.g. for "signed in with <nickname>"
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct UsedAuthenticator {
+    // base64url, as passkey.cred.cred_id of /me/authenticators
+    cred_id: String,
+    nickname: Option<String>,
+    user_agent_short: String,
+}
+
+// the user (as before, flattened), plus the credential that signed in
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct AuthenticateFinishResponse {
+    #[serde(flatten)]
+    user: User,
+    used_authenticator: UsedAuthenticator,
+}
+
+async fn get_used_authenticator(
+    app_state: &AppState,
+    user_id: Uuid,
+    passkey_id: String,
+) -> Result<UsedAuthenticator, WebauthnError> {
+    let (nickname, user_agent_short) = app_state
+        .db
+        .timed_call("get_authenticator_names", {
+            let passkey_id = passkey_id.clone();
+            move |conn| {
+                queries::get_authenticator_names(conn, user_id, passkey_id).map_err(|e| e.into())
+            }
+        })
+        .await
+        .map_err(|e| {
+            error!("get_authenticator_names: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+    Ok(UsedAuthenticator {
+        cred_id: passkey_id,
+        nickname,
+        user_agent_short,
+    })
+}
+
 // The browser and user have completed navigator.credentials.get.
 // We need to check if a user exists for the claimed uuid, check if
 // the used credential belongs to the user, and verify the signature.
+#[utoipa::path(post, path = "/authenticate_finish",
+    request_body(
+        description = "PublicKeyCredential, json, cbor or msgpack",
+        content = serde_json::Value
+    ),
+    responses(
+        (status = 200, body = AuthenticateFinishResponse),
+        (status = 500, description = "WebauthnError", body = String),
+    )
+)]
 pub async fn finish_authentication(
     Extension(app_state): Extension<AppState>,
     session: Session,
     cookies: Cookies,
     ExtractMe(me): ExtractMe,
-    Json(auth_input): Json<PublicKeyCredential>,
+    ClientIp(ip): ClientIp,
+    Payload(auth_input): Payload<PublicKeyCredential>,
 ) -> Result<impl IntoResponse, WebauthnError> {
     if me.is_some() {
         return Err(WebauthnError::AlreadySignedIn);
     }
 
     let auth_state: DiscoverableAuthentication = session
-        .get("auth_state")
+        .get(&app_state.config.session_key(AUTH_STATE))
         .await
         .map_err(|e| {
             error!("Failed to get auth_state from session: {:?}", e);
@@ -327,47 +835,22 @@ pub async fn finish_authentication(
             WebauthnError::CorruptSession
         })?;
 
-    session.remove_value("auth_state").await.map_err(|e| {
-        error!("Failed to remove auth_state from session: {:?}", e);
-        WebauthnError::CorruptSession
-    })?;
-
-    let (user_id, cred_id) = match app_state
-        .webauthn
-        .identify_discoverable_authentication(&auth_input)
-    {
-        Ok(creds) => creds,
-        Err(e) => {
-            info!("Error in finish_authentication: {:?}", e);
-            return Err(WebauthnError::UserAndCredentialDontMatch);
-        }
-    };
-
-    // make string from &[u8] to be able to copy it and not have lifetime
-    // dependency on auth_input.
-    let passkey_id = Base64UrlSafeData::from(cred_id).to_string();
-
-    // try to find the used passkey for the claimed user_id
-    let passkey = app_state
-        .db
-        .conn
-        .call({
-            let passkey_id = passkey_id.clone();
-            move |conn| {
-                queries::get_passkey_for_user_and_passkey_id(conn, user_id, passkey_id)
-                    .map_err(|e| e.into())
-            }
-        })
+    session
+        .remove_value(&app_state.config.session_key(AUTH_STATE))
         .await
         .map_err(|e| {
-            error!("get_passkey_for_user_and_passkey_id: {:?}", e);
-            WebauthnError::GenericDatabaseError
-        })?
-        .ok_or_else(|| {
-            error!("Failed to get passkey for claimed user_id.");
-            WebauthnError::UserNotFound
+            error!("Failed to remove auth_state from session: {:?}", e);
+            WebauthnError::CorruptSession
         })?;
 
+    let passkey_id = identify_passkey_id(&app_state, &auth_input)?;
+
+    // the owner of the passkey, not the user handle of the device: merged
+    // passkeys keep the handle of the merged-away account
+    // not found: the passkey is still on the device, but its account was
+    // deleted, or the credential was removed
+    let (user_id, passkey) = get_passkey(&app_state, passkey_id.clone()).await?;
+
     let res = match app_state.webauthn.finish_discoverable_authentication(
         &auth_input,
         auth_state,
@@ -378,14 +861,13 @@ pub async fn finish_authentication(
             if auth_result.needs_update() {
                 app_state
                     .db
-                    .conn
-                    .call({
+                    .timed_call_retry("update_passkey_for_user_and_passkey_id", {
                         let passkey_id = passkey_id.clone();
                         move |conn| {
                             queries::update_passkey_for_user_and_passkey_id(
                                 conn,
                                 user_id,
-                                passkey_id,
+                                passkey_id.clone(),
                                 auth_result.counter(),
                                 auth_result.backup_state(),
                                 auth_result.backup_eligible(),
@@ -396,31 +878,683 @@ pub async fn finish_authentication(
                     .await
                     .map_err(|e| {
                         error!("update_passkey_for_user_and_passkey_id: {:?}", e);
-                        WebauthnError::GenericDatabaseError
+                        WebauthnError::from_db(&e)
                     })?;
             }
 
             // load user
             let user = app_state
                 .db
-                .conn
-                .call(move |conn| queries::get_user_by_id(conn, user_id).map_err(|e| e.into()))
+                .timed_call("get_user_by_id", move |conn| {
+                    queries::get_user_by_id(conn, user_id).map_err(|e| e.into())
+                })
                 .await
                 .map_err(|e| {
                     error!("get_user_by_id: {:?}", e);
-                    WebauthnError::GenericDatabaseError
+                    WebauthnError::from_db(&e)
                 })?;
+            let used_authenticator =
+                get_used_authenticator(&app_state, user_id, passkey_id).await?;
 
             // set session authenticated
-            session::set_me_authenticated(user.clone(), session, cookies).await?;
+            session::set_me_authenticated(user.clone(), session, cookies, &app_state.config)
+                .await?;
 
-            Json(user)
+            Json(AuthenticateFinishResponse {
+                user,
+                used_authenticator,
+            })
         }
         Err(e) => {
             info!("Error in finish_authentication: {:?}", e);
+            return Err(ceremony_error(
+                e,
+                auth_input.response.client_data_json.as_ref(),
+                &app_state.config,
+            ));
+        }
+    };
+    info!("Authentication Successful! ip: {:?}", ip);
+    Ok(res)
+}
+
+// Non-discoverable fallback: authenticators without resident keys can't be
+// found by start_discoverable_authentication ("no passkeys found"). The client
+// tries discoverable authentication first and offers to enter the username
+// as fallback. The credentials of the user are sent as allow credentials
+// (with their transports), so non-resident credentials are found.
+// the response tells whether a username exists, like register_start does.
+pub async fn start_authentication_for(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMe(me): ExtractMe,
+    Path(username): Path<String>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    info!("Start Authentication for username");
+
+    if me.is_some() {
+        return Err(WebauthnError::AlreadySignedIn);
+    }
+
+    let username = normalize_username(&username);
+    let user = app_state
+        .db
+        .timed_call("get_user_by_username", move |conn| {
+            queries::get_user_by_username(conn, &username).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_user_by_username: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?
+        .ok_or(WebauthnError::UserNotFound)?;
+
+    let authenticators = app_state
+        .db
+        .timed_call("get_authenticators_for_user_id", move |conn| {
+            queries::get_authenticators_for_user_id(conn, user.id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_authenticators_for_user: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+    let passkeys: Vec<Passkey> = authenticators.iter().map(|a| a.passkey.clone()).collect();
+
+    session
+        .remove_value(&app_state.config.session_key(AUTH_FOR_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove auth_for_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    let res = match app_state.webauthn.start_passkey_authentication(&passkeys) {
+        Ok((mut rcr, auth_state)) => {
+            // transport hints help the browser to show the right prompt
+            for allow_credential in rcr.public_key.allow_credentials.iter_mut() {
+                let id: &[u8] = allow_credential.id.as_ref();
+                allow_credential.transports = authenticators
+                    .iter()
+                    .find(|a| AsRef::<[u8]>::as_ref(a.passkey.cred_id()) == id)
+                    .and_then(|a| serde_json::from_value(serde_json::json!(a.transports)).ok());
+            }
+            // Store auth state in session. This is only save because session
+            // store is server side. A cookie store would enable replay attacks.
+            session
+                .insert(
+                    &app_state.config.session_key(AUTH_FOR_STATE),
+                    (user.id, auth_state),
+                )
+                .await
+                .map_err(|e| {
+                    error!("Failed to insert auth_for_state into session: {:?}", e);
+                    WebauthnError::CorruptSession
+                })?;
+            Json(rcr)
+        }
+        Err(e) => {
+            info!("Error in start_authentication_for: {:?}", e);
             return Err(WebauthnError::Unknown);
         }
     };
-    info!("Authentication Successful!");
     Ok(res)
 }
+
+pub async fn finish_authentication_for(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    cookies: Cookies,
+    ExtractMe(me): ExtractMe,
+    ClientIp(ip): ClientIp,
+    Payload(auth_input): Payload<PublicKeyCredential>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    if me.is_some() {
+        return Err(WebauthnError::AlreadySignedIn);
+    }
+
+    let (user_id, auth_state): (Uuid, PasskeyAuthentication) = session
+        .get(&app_state.config.session_key(AUTH_FOR_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to get auth_for_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?
+        .ok_or_else(|| {
+            error!("Failed to get session");
+            WebauthnError::CorruptSession
+        })?;
+
+    session
+        .remove_value(&app_state.config.session_key(AUTH_FOR_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove auth_for_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    // verifies the credential is one of the allow credentials of the user
+    let auth_result = app_state
+        .webauthn
+        .finish_passkey_authentication(&auth_input, &auth_state)
+        .map_err(|e| {
+            info!("Error in finish_authentication_for: {:?}", e);
+            ceremony_error(
+                e,
+                auth_input.response.client_data_json.as_ref(),
+                &app_state.config,
+            )
+        })?;
+
+    let cred_id: &[u8] = auth_result.cred_id().as_ref();
+    let passkey_id = Base64UrlSafeData::from(cred_id).to_string();
+
+    // Update the credential counter if needed.
+    if auth_result.needs_update() {
+        app_state
+            .db
+            .timed_call_retry("update_passkey_for_user_and_passkey_id", {
+                let passkey_id = passkey_id.clone();
+                move |conn| {
+                    queries::update_passkey_for_user_and_passkey_id(
+                        conn,
+                        user_id,
+                        passkey_id.clone(),
+                        auth_result.counter(),
+                        auth_result.backup_state(),
+                        auth_result.backup_eligible(),
+                    )
+                    .map_err(|e| e.into())
+                }
+            })
+            .await
+            .map_err(|e| {
+                error!("update_passkey_for_user_and_passkey_id: {:?}", e);
+                WebauthnError::from_db(&e)
+            })?;
+    }
+
+    let user = app_state
+        .db
+        .timed_call("get_user_by_id", move |conn| {
+            queries::get_user_by_id(conn, user_id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_user_by_id: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+
+    let used_authenticator = get_used_authenticator(&app_state, user_id, passkey_id).await?;
+
+    session::set_me_authenticated(user.clone(), session, cookies, &app_state.config).await?;
+
+    info!("Authentication for username Successful! ip: {:?}", ip);
+    Ok(Json(AuthenticateFinishResponse {
+        user,
+        used_authenticator,
+    }))
+}
+
+// Account merge: a signed in user proves control of another account (e.g. a
+// duplicate registered because the first passkey didn't sync) by
+// authenticating with its credential. The authenticators of the other account
+// are moved to the signed in user and the other account is deleted.
+//
+// checks, in order:
+// - the caller is signed in (ExtractMeEnsure)
+// - the challenge was issued by merge_start to this session and is single use
+//   (merge_state is removed before verification)
+// - the credential of the assertion exists in the db, its owner (not the user
+//   handle of the device, see get_passkey) is the other account
+// - the other account is not the signed in user
+// - finish_discoverable_authentication verifies the signature with the stored
+//   public key, the challenge, origin and rp id and the counter (cloned keys)
+// - both accounts together have at most MAX_AUTHENTICATORS_PER_USER
+// only then the authenticators are moved and the sessions of the deleted
+// account are deleted, in one call.
+pub async fn start_merge(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeEnsure(_me): ExtractMeEnsure,
+) -> Result<impl IntoResponse, WebauthnError> {
+    info!("Start merge");
+
+    session
+        .remove_value(&app_state.config.session_key(MERGE_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove merge_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    let res = match app_state.webauthn.start_discoverable_authentication() {
+        Ok((rcr, merge_state)) => {
+            session
+                .insert(&app_state.config.session_key(MERGE_STATE), merge_state)
+                .await
+                .map_err(|e| {
+                    error!("Failed to insert merge_state into session: {:?}", e);
+                    WebauthnError::CorruptSession
+                })?;
+            Json(rcr)
+        }
+        Err(e) => {
+            info!("Error in start_merge: {:?}", e);
+            return Err(WebauthnError::Unknown);
+        }
+    };
+    Ok(res)
+}
+
+pub async fn finish_merge(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeEnsure(me): ExtractMeEnsure,
+    Payload(auth_input): Payload<PublicKeyCredential>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    #[derive(serde::Serialize)]
+    struct MergeResponse {
+        merged_username: String,
+        merged_authenticators: usize,
+    }
+
+    let merge_state: DiscoverableAuthentication = session
+        .get(&app_state.config.session_key(MERGE_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to get merge_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?
+        .ok_or_else(|| {
+            error!("Failed to get session");
+            WebauthnError::CorruptSession
+        })?;
+
+    session
+        .remove_value(&app_state.config.session_key(MERGE_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove merge_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    let passkey_id = identify_passkey_id(&app_state, &auth_input)?;
+    let (other_user_id, passkey) = get_passkey(&app_state, passkey_id.clone()).await?;
+
+    if other_user_id == me.id {
+        return Err(WebauthnError::MergeWithSelf);
+    }
+
+    let auth_result = app_state
+        .webauthn
+        .finish_discoverable_authentication(
+            &auth_input,
+            merge_state,
+            &[DiscoverableKey::from(passkey)],
+        )
+        .map_err(|e| {
+            info!("Error in finish_merge: {:?}", e);
+            ceremony_error(
+                e,
+                auth_input.response.client_data_json.as_ref(),
+                &app_state.config,
+            )
+        })?;
+
+    let other_user = app_state
+        .db
+        .timed_call("get_user_by_id", move |conn| {
+            queries::get_user_by_id(conn, other_user_id).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| {
+            error!("get_user_by_id: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?;
+
+    let hard_delete = app_state.config.hard_delete;
+    let max_authenticators = app_state.config.max_authenticators_per_user;
+    let user_key = app_state.config.session_key(session::AUTHENTICATED_USER);
+    let merged_authenticators = app_state
+        .db
+        .timed_call("merge_users", move |conn| {
+            if auth_result.needs_update() {
+                queries::update_passkey_for_user_and_passkey_id(
+                    conn,
+                    other_user_id,
+                    passkey_id,
+                    auth_result.counter(),
+                    auth_result.backup_state(),
+                    auth_result.backup_eligible(),
+                )?;
+            }
+            let merged =
+                queries::merge_users(conn, me.id, other_user_id, hard_delete, max_authenticators)?;
+            if merged.is_some() {
+                queries::delete_sessions_of_user(conn, other_user_id, &user_key)?;
+            }
+            Ok(merged)
+        })
+        .await
+        .map_err(|e| {
+            error!("merge_users: {:?}", e);
+            WebauthnError::from_db(&e)
+        })?
+        .ok_or_else(|| {
+            info!(
+                "Merge of {} into {} exceeds MAX_AUTHENTICATORS_PER_USER",
+                other_user_id, me.username
+            );
+            WebauthnError::TooManyAuthenticators
+        })?;
+
+    app_state
+        .events
+        .emit(other_user_id, UserEvent::SessionRevoked);
+    app_state.events.emit(
+        me.id,
+        UserEvent::AuthenticatorAdded {
+            user_agent_short: format!("merged from {}", other_user.username),
+        },
+    );
+
+    info!(
+        "Merged {} ({} authenticators) into {}",
+        other_user.username, merged_authenticators, me.username
+    );
+    Ok(Json(MergeResponse {
+        merged_username: other_user.username,
+        merged_authenticators,
+    }))
+}
+
+// Reauth (step up): the signed in user confirms with one of their passkeys
+// before a sensitive operation. Same ceremony as merge, but the assertion must
+// be of the signed in user. The session is left as is (no cycle_id, no new
+// sign in), the result is a short lived token, see reauth.rs.
+pub async fn start_reauth(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeEnsure(_me): ExtractMeEnsure,
+) -> Result<impl IntoResponse, WebauthnError> {
+    info!("Start reauth");
+
+    session
+        .remove_value(&app_state.config.session_key(REAUTH_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove reauth_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    let res = match app_state.webauthn.start_discoverable_authentication() {
+        Ok((rcr, reauth_state)) => {
+            session
+                .insert(&app_state.config.session_key(REAUTH_STATE), reauth_state)
+                .await
+                .map_err(|e| {
+                    error!("Failed to insert reauth_state into session: {:?}", e);
+                    WebauthnError::CorruptSession
+                })?;
+            Json(rcr)
+        }
+        Err(e) => {
+            info!("Error in start_reauth: {:?}", e);
+            return Err(WebauthnError::Unknown);
+        }
+    };
+    Ok(res)
+}
+
+pub async fn finish_reauth(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeEnsure(me): ExtractMeEnsure,
+    Payload(auth_input): Payload<PublicKeyCredential>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    #[derive(serde::Serialize)]
+    struct ReauthResponse {
+        // send as X-Reauth-Token
+        reauth_token: String,
+        // unix timestamp
+        expires_at: i64,
+    }
+
+    let reauth_state: DiscoverableAuthentication = session
+        .get(&app_state.config.session_key(REAUTH_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to get reauth_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?
+        .ok_or_else(|| {
+            error!("Failed to get session");
+            WebauthnError::CorruptSession
+        })?;
+
+    session
+        .remove_value(&app_state.config.session_key(REAUTH_STATE))
+        .await
+        .map_err(|e| {
+            error!("Failed to remove reauth_state from session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+
+    let passkey_id = identify_passkey_id(&app_state, &auth_input)?;
+    let (user_id, passkey) = get_passkey(&app_state, passkey_id.clone()).await?;
+
+    if user_id != me.id {
+        info!("Reauth of {} with a passkey of {user_id}", me.id);
+        return Err(WebauthnError::ReauthOtherUser);
+    }
+
+    let auth_result = app_state
+        .webauthn
+        .finish_discoverable_authentication(
+            &auth_input,
+            reauth_state,
+            &[DiscoverableKey::from(passkey)],
+        )
+        .map_err(|e| {
+            info!("Error in finish_reauth: {:?}", e);
+            ceremony_error(
+                e,
+                auth_input.response.client_data_json.as_ref(),
+                &app_state.config,
+            )
+        })?;
+
+    if auth_result.needs_update() {
+        app_state
+            .db
+            .timed_call_retry("update_passkey_for_user_and_passkey_id", move |conn| {
+                queries::update_passkey_for_user_and_passkey_id(
+                    conn,
+                    user_id,
+                    passkey_id.clone(),
+                    auth_result.counter(),
+                    auth_result.backup_state(),
+                    auth_result.backup_eligible(),
+                )
+                .map_err(|e| e.into())
+            })
+            .await
+            .map_err(|e| {
+                error!("update_passkey_for_user_and_passkey_id: {:?}", e);
+                WebauthnError::from_db(&e)
+            })?;
+    }
+
+    let (reauth_token, expires_at) = reauth::sign_token(app_state.reauth_key.as_ref(), me.id);
+    info!("Reauth of {}", me.username);
+    Ok(Json(ReauthResponse {
+        reauth_token,
+        expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn unicode_config() -> Config {
+        Config {
+            username_allow_unicode: true,
+            ..test_config()
+        }
+    }
+
+    #[test]
+    fn validate_username_length() {
+        let config = test_config();
+        assert!(validate_username("abc", &config).is_ok());
+        assert!(validate_username(&"a".repeat(USERNAME_MAX_LEN), &config).is_ok());
+        assert!(matches!(
+            validate_username("ab", &config),
+            Err(WebauthnError::InvalidUsername)
+        ));
+        assert!(matches!(
+            validate_username(&"a".repeat(USERNAME_MAX_LEN + 1), &config),
+            Err(WebauthnError::InvalidUsername)
+        ));
+        // characters, not bytes
+        assert!(validate_username(&"ä".repeat(USERNAME_MAX_LEN), &unicode_config()).is_ok());
+    }
+
+    #[test]
+    fn validate_username_ascii_only_by_default() {
+        let config = test_config();
+        assert!(validate_username("patte_01-x.y", &config).is_ok());
+        for username in ["jürgen", "josé", "ivan ivanov", "a/b", "a@b"] {
+            assert!(
+                matches!(
+                    validate_username(username, &config),
+                    Err(WebauthnError::InvalidUsernameCharacters)
+                ),
+                "{username:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_username_international() {
+        let config = unicode_config();
+        for username in ["jürgen", "josé", "Øyvind", "иван", "李小龍", "محمد"] {
+            assert!(
+                validate_username(&normalize_username(username), &config).is_ok(),
+                "{username:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_username_rejects_invisible_characters() {
+        let config = unicode_config();
+        for username in [
+            // control
+            "abc\u{0}",
+            "abc\n",
+            "abc\u{7f}",
+            // format: zero width space, joiner, rtl override, bom
+            "ab\u{200b}c",
+            "ab\u{200d}c",
+            "\u{202e}abc",
+            "\u{feff}abc",
+            // whitespace
+            "ab c",
+            "ab\u{a0}c",
+            // emoji
+            "abc\u{1f600}",
+        ] {
+            assert!(
+                matches!(
+                    validate_username(username, &config),
+                    Err(WebauthnError::InvalidUsernameCharacters)
+                ),
+                "{username:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_username_composes() {
+        // "é" composed (U+00E9) and decomposed (e + U+0301)
+        let composed = "jos\u{e9}";
+        let decomposed = "jose\u{301}";
+        assert_ne!(composed, decomposed);
+        assert_eq!(normalize_username(composed), normalize_username(decomposed));
+        assert_eq!(normalize_username(decomposed), composed);
+        // hangul syllable from its jamo
+        assert_eq!(
+            normalize_username("\u{1100}\u{1161}\u{11a8}"),
+            normalize_username("\u{ac01}")
+        );
+        // ascii is unchanged
+        assert_eq!(normalize_username("patte_01"), "patte_01");
+    }
+
+    #[test]
+    fn normalize_username_keeps_compatibility_characters() {
+        // NFC, not NFKC: the ligature "ﬁ" isn't "fi"
+        assert_ne!(normalize_username("\u{fb01}le"), "file");
+    }
+
+    #[test]
+    fn reserved_usernames() {
+        let config = test_config();
+        for username in [
+            "admin", "Admin", "ADMIN", " admin", "admin\t", "Root", "sUpPoRt",
+        ] {
+            assert!(is_reserved_username(username, &config), "{username:?}");
+        }
+        for username in ["admin1", "admins", "my_admin", "ad-min", "patte"] {
+            assert!(!is_reserved_username(username, &config), "{username:?}");
+        }
+    }
+
+    #[test]
+    fn reserved_usernames_configured() {
+        let config = Config {
+            reserved_usernames: vec!["ärger".to_string()],
+            ..unicode_config()
+        };
+        assert!(is_reserved_username("Ärger", &config));
+        assert!(!is_reserved_username("admin", &config));
+    }
+
+    #[test]
+    fn weak_algorithm() {
+        // the default WEBAUTHN_ALGORITHMS
+        let config = Config {
+            webauthn_algorithms: vec![COSEAlgorithm::ES256, COSEAlgorithm::RS256],
+            ..test_config()
+        };
+        assert!(!is_weak_algorithm(COSEAlgorithm::ES256, &config));
+        assert!(is_weak_algorithm(COSEAlgorithm::RS256, &config));
+
+        let config = Config {
+            webauthn_algorithms: vec![COSEAlgorithm::RS256, COSEAlgorithm::ES256],
+            ..test_config()
+        };
+        assert!(!is_weak_algorithm(COSEAlgorithm::RS256, &config));
+        assert!(is_weak_algorithm(COSEAlgorithm::ES256, &config));
+    }
+
+    #[test]
+    fn validate_username_combining_characters() {
+        let config = unicode_config();
+        // composed by normalize_username
+        assert!(validate_username(&normalize_username("e\u{301}va"), &config).is_ok());
+        // no composed form: combining marks aren't alphanumeric
+        assert!(matches!(
+            validate_username(&normalize_username("abc\u{301}\u{301}"), &config),
+            Err(WebauthnError::InvalidUsernameCharacters)
+        ));
+        assert!(matches!(
+            validate_username("x\u{20dd}yz", &config),
+            Err(WebauthnError::InvalidUsernameCharacters)
+        ));
+    }
+}
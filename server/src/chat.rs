@@ -0,0 +1,538 @@
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, Query,
+    },
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::{
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, error::TrySendError},
+    },
+    time::Duration,
+};
+use tower_sessions::Session;
+
+use crate::{admin::ExtractAdmin, models::User, session, state::AppState};
+
+// websocket chat for signed in users
+// adapted from the axum chat example:
+// source: https://github.com/tokio-rs/axum/blob/main/examples/chat/src/main.rs
+
+// number of messages kept in memory for replays
+const RECENT_MESSAGES_MAX: usize = 200;
+
+// number of messages replayed to joining clients without ?since
+const JOIN_REPLAY_MAX: usize = 20;
+
+// max number of messages replayed to reconnecting clients with ?since
+const RECONNECT_REPLAY_MAX: usize = 100;
+
+// min time between announcements, against accidental spam (e.g. a retry loop)
+const ANNOUNCE_MIN_INTERVAL_SECONDS: i64 = 10;
+
+// max length of an announcement
+const ANNOUNCE_MAX_LEN: usize = 500;
+
+// suggested wait of clients rejected because the chat is full
+const CHAT_FULL_RETRY_AFTER_SECONDS: u64 = 30;
+
+// kind of the frame broadcast on graceful shutdown, see notify_shutdown
+const SERVER_SHUTDOWN: &str = "server_shutdown";
+
+// time for the connections to send the server_shutdown frame before exit
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(2);
+
+// presence:
+// connected_usernames counts the connections per username for the joined/left
+// messages and the online count, it's updated when a connection starts and
+// ends. A connection which dies without its cleanup (a panic in websocket())
+// would stay counted for the rest of the uptime. Every counted (not silent)
+// connection is also in the live_connections registry with a Weak to a token
+// only the connection task holds: the token is dropped when the task ends,
+// whichever way. The reaper (reap_presence) evicts entries with a dead token and recounts
+// connected_usernames from the registry.
+// recent_messages needs no compaction, it's bounded by RECENT_MESSAGES_MAX.
+
+// interval of the presence reaper, ghosts are rare, no need to be quick
+pub const PRESENCE_REAP_INTERVAL: Duration = Duration::from_secs(300);
+
+// backpressure:
+// all connections share one broadcast channel with CHAT_BROADCAST_CAPACITY
+// slots. Sending never blocks. The send half of a connection is split in two:
+// a forwarder moves broadcast messages (and notices) into a bounded queue of
+// CHAT_SEND_BUFFER messages right away, a writer writes the queue to the
+// socket. A transiently slow socket fills the queue instead of falling behind
+// on the broadcast channel. If the queue is full, the client is too slow to
+// keep up: it gets a notice and is disconnected (close 1013, try again later),
+// it can fetch the missed messages by reconnecting with ?since=<last id>.
+// If the forwarder itself falls more than CHAT_BROADCAST_CAPACITY behind (a
+// starved runtime), the oldest messages are lost: the client receives a
+// notice with the number of missed messages and stays connected.
+
+// frame sent to the clients, serialized as json
+//
+// ordering contract:
+// - ids are assigned by the server, never by clients
+// - ids are strictly increasing per server process and seeded with the
+//   startup time in microseconds, so they keep increasing across restarts
+// - the order in which frames are broadcast and replayed is the id order
+// clients order by id and drop frames with an id they already know, this
+// dedupes replays to reconnecting clients (and echoes of a future bridge).
+// exception: notices to a single client (e.g. missed messages) have id 0, are
+// not part of the ordered stream and are shown as they arrive.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub id: u64,
+    // server time when the message was broadcast (rfc3339), kept on replays
+    pub ts: DateTime<Utc>,
+    pub body: String,
+    // set for frames clients act on: "server_shutdown" (reconnect after a
+    // moment), omitted for messages and notices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+}
+
+// seed for AppState.next_message_id, see ordering contract above
+// (~2^51 in 2024, still below js' Number.MAX_SAFE_INTEGER)
+pub fn initial_message_id() -> u64 {
+    Utc::now().timestamp_micros() as u64
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatParams {
+    // id of the last message the client received before the connection dropped
+    since: Option<u64>,
+    // no joined/left messages and not counted online, e.g. bots and read
+    // only dashboards. They can still send and receive messages.
+    #[serde(default)]
+    silent: bool,
+}
+
+pub async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    Query(params): Query<ChatParams>,
+) -> Response {
+    let Some(me) = session::current_user(&session, &app_state.config).await else {
+        return session::unauthorized_response();
+    };
+    let eligible_at = me.created_at
+        + chrono::Duration::from_std(app_state.config.chat_min_account_age).unwrap_or_default();
+    if eligible_at > Utc::now() {
+        info!("Account of {} too new for the chat", me.username);
+        return account_too_new_response(eligible_at);
+    }
+    // counted before the upgrade, the guard moves into the connection task
+    let Some(guard) = ConnectionGuard::acquire(&app_state) else {
+        warn!(
+            "Chat is full ({} connections), rejected {}",
+            app_state.config.chat_max_connections, me.username
+        );
+        return chat_full_response();
+    };
+    ws.on_upgrade(move |socket| async move {
+        websocket(socket, app_state, me, params.since, params.silent).await;
+        drop(guard);
+    })
+}
+
+// rejections happen before the protocol switch, so it's a plain http response
+// the client can show and schedule the reconnect with
+fn chat_full_response() -> Response {
+    let body = serde_json::json!({
+        "error": "chat_full",
+        "retry_after": CHAT_FULL_RETRY_AFTER_SECONDS,
+    });
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, CHAT_FULL_RETRY_AFTER_SECONDS.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+// see CHAT_MIN_ACCOUNT_AGE_MINUTES, the client can tell when to come back
+fn account_too_new_response(eligible_at: DateTime<Utc>) -> Response {
+    let body = serde_json::json!({
+        "error": "account_too_new",
+        "message": "Your account is too new for the chat.",
+        "eligible_at": eligible_at,
+    });
+    (StatusCode::FORBIDDEN, Json(body)).into_response()
+}
+
+// a slot of AppState.chat_connections, released on drop, also if the upgrade
+// fails or the connection task panics
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn acquire(state: &AppState) -> Option<Self> {
+        let max = state.config.chat_max_connections;
+        state
+            .chat_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ConnectionGuard(state.chat_connections.clone()))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// entry of AppState.live_connections, alive while the connection task runs
+pub struct LiveConnection {
+    username: String,
+    alive: Weak<()>,
+}
+
+// one task per connection, split into a send and a receive half
+// silent connections skip the presence (see ChatParams)
+async fn websocket(stream: WebSocket, state: AppState, me: User, since: Option<u64>, silent: bool) {
+    let (mut sender, mut receiver) = stream.split();
+    let username = me.username;
+
+    // dropped with this task, also on panic, see presence above
+    let alive = Arc::new(());
+    let connection_id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+
+    // subscribe before replaying, so nothing sent in between is lost
+    let mut rx = state.tx.subscribe();
+
+    // reconnecting clients get what they missed, others the last few messages
+    let replay_messages = recent_messages_to_replay(&state.recent_messages.lock().unwrap(), since);
+    for msg in replay_messages {
+        if sender.send(to_frame(&msg)).await.is_err() {
+            return;
+        }
+    }
+
+    // a user can be connected with multiple tabs, count connections per username
+    if !silent {
+        state.live_connections.lock().unwrap().insert(
+            connection_id,
+            LiveConnection {
+                username: username.clone(),
+                alive: Arc::downgrade(&alive),
+            },
+        );
+        let online_count = {
+            let mut connected_usernames = state.connected_usernames.lock().unwrap();
+            *connected_usernames.entry(username.clone()).or_insert(0) += 1;
+            connected_usernames.len()
+        };
+        broadcast(&state, format!("👋 {username} joined."), true);
+        broadcast(&state, format!("👥 {online_count} online"), false);
+    }
+
+    // notices to this client only, sent by the send half
+    let (notice_tx, mut notice_rx) = mpsc::unbounded_channel::<ChatMessage>();
+
+    // forward broadcast messages and notices to this client, see backpressure
+    let mut send_task = tokio::spawn({
+        let username = username.clone();
+        let send_buffer = state.config.chat_send_buffer;
+        async move {
+            let (queue_tx, mut queue_rx) = mpsc::channel::<ChatMessage>(send_buffer.max(1));
+            let overflowed = AtomicBool::new(false);
+            let overflowed = &overflowed;
+
+            let forward = async move {
+                loop {
+                    let msg = tokio::select! {
+                        msg = rx.recv() => match msg {
+                            Ok(msg) => msg,
+                            // continue with the oldest message still buffered
+                            Err(RecvError::Lagged(missed)) => {
+                                warn!("Chat connection of {username} lagged, missed {missed} messages");
+                                notice(format!("⚠️ You missed {missed} messages."))
+                            }
+                            Err(RecvError::Closed) => break,
+                        },
+                        Some(msg) = notice_rx.recv() => msg,
+                        // the writer is done
+                        _ = queue_tx.closed() => break,
+                    };
+                    match queue_tx.try_send(msg) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            warn!("Chat connection of {username} too slow, send buffer full");
+                            overflowed.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        Err(TrySendError::Closed(_)) => break,
+                    }
+                }
+            };
+
+            let write = async move {
+                while let Some(msg) = queue_rx.recv().await {
+                    // don't drain the queue to a client which is disconnected anyway
+                    if overflowed.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if sender.send(to_frame(&msg)).await.is_err() {
+                        return;
+                    }
+                    if msg.kind == Some(SERVER_SHUTDOWN) {
+                        let close_frame = CloseFrame {
+                            code: close_code::RESTART,
+                            reason: "server restarting".into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(close_frame))).await;
+                        return;
+                    }
+                }
+                if overflowed.load(Ordering::SeqCst) {
+                    let msg = notice("⚠️ Your connection is too slow, please reconnect.".into());
+                    let _ = sender.send(to_frame(&msg)).await;
+                    let close_frame = CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "too slow".into(),
+                    };
+                    let _ = sender.send(Message::Close(Some(close_frame))).await;
+                }
+            };
+
+            tokio::join!(forward, write);
+        }
+    });
+
+    // broadcast messages of this client to everyone
+    let mut recv_task = tokio::spawn({
+        let state = state.clone();
+        let username = username.clone();
+        async move {
+            while let Some(frame) = receiver.next().await {
+                match frame {
+                    Ok(Message::Text(text)) => {
+                        broadcast(&state, format!("{username}: {text}"), true);
+                    }
+                    Ok(Message::Binary(data)) => {
+                        warn!(
+                            "Chat connection of {username} sent {} bytes binary, ignored",
+                            data.len()
+                        );
+                        let _ =
+                            notice_tx.send(notice("⚠️ Binary messages are not supported.".into()));
+                    }
+                    // pings are answered with pongs by axum (tungstenite)
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
+                    // the close is answered by axum (tungstenite), clean shutdown
+                    Ok(Message::Close(close_frame)) => {
+                        debug!("Chat connection of {username} closed: {:?}", close_frame);
+                        break;
+                    }
+                    Err(e) => {
+                        info!("Chat connection of {username} failed: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    // if one of the tasks exits, abort the other
+    tokio::select! {
+        _ = &mut send_task => recv_task.abort(),
+        _ = &mut recv_task => send_task.abort(),
+    };
+
+    if silent {
+        return;
+    }
+    state
+        .live_connections
+        .lock()
+        .unwrap()
+        .remove(&connection_id);
+    let online_count = {
+        let mut connected_usernames = state.connected_usernames.lock().unwrap();
+        if let Some(count) = connected_usernames.get_mut(&username) {
+            *count -= 1;
+            if *count == 0 {
+                connected_usernames.remove(&username);
+            }
+        }
+        connected_usernames.len()
+    };
+    broadcast(&state, format!("👋 {username} left."), true);
+    broadcast(&state, format!("👥 {online_count} online"), false);
+}
+
+// evicts connections which ended without cleanup from the registry and
+// recounts connected_usernames from it. The corrected online count is
+// broadcast if it changed.
+pub async fn continuously_reap_presence(state: AppState, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        reap_presence(&state);
+    }
+}
+
+fn reap_presence(state: &AppState) {
+    let live_counts = {
+        let mut live_connections = state.live_connections.lock().unwrap();
+        live_connections.retain(|id, connection| {
+            let alive = connection.alive.strong_count() > 0;
+            if !alive {
+                warn!(
+                    "Evicting chat connection {id} of {}, ended without cleanup",
+                    connection.username
+                );
+            }
+            alive
+        });
+        let mut live_counts: HashMap<String, usize> = HashMap::new();
+        for connection in live_connections.values() {
+            *live_counts.entry(connection.username.clone()).or_insert(0) += 1;
+        }
+        live_counts
+    };
+
+    // connections starting or ending right now update both maps, they are
+    // consistent again by the next run at the latest
+    let online_count = {
+        let mut connected_usernames = state.connected_usernames.lock().unwrap();
+        if *connected_usernames == live_counts {
+            return;
+        }
+        *connected_usernames = live_counts;
+        connected_usernames.len()
+    };
+    info!("Corrected chat presence, {online_count} online");
+    broadcast(state, format!("👥 {online_count} online"), false);
+}
+
+// messages newer than since (bounded) or the last messages if since is None
+// if since is older than the oldest remembered message, the gap can't be filled.
+fn recent_messages_to_replay(
+    recent_messages: &VecDeque<ChatMessage>,
+    since: Option<u64>,
+) -> Vec<ChatMessage> {
+    let (newer, max): (Vec<&ChatMessage>, usize) = match since {
+        Some(since) => (
+            recent_messages.iter().filter(|m| m.id > since).collect(),
+            RECONNECT_REPLAY_MAX,
+        ),
+        None => (recent_messages.iter().collect(), JOIN_REPLAY_MAX),
+    };
+    newer[newer.len().saturating_sub(max)..]
+        .iter()
+        .map(|m| (*m).clone())
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Announcement {
+    message: String,
+}
+
+// operator announcement to everyone in the chat (e.g. maintenance), without
+// being a connected user. Prefixed with 📢 and remembered for joining clients.
+pub async fn announce_handler(
+    _admin: ExtractAdmin,
+    Extension(state): Extension<AppState>,
+    Json(announcement): Json<Announcement>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let message = announcement.message.trim();
+    if message.is_empty() || message.len() > ANNOUNCE_MAX_LEN {
+        return Err((StatusCode::BAD_REQUEST, "Invalid message"));
+    }
+
+    let now = Utc::now().timestamp();
+    state
+        .last_announcement
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            (now - last >= ANNOUNCE_MIN_INTERVAL_SECONDS).then_some(now)
+        })
+        .map_err(|_| (StatusCode::TOO_MANY_REQUESTS, "Too many announcements"))?;
+
+    info!("Chat announcement: {message}");
+    broadcast(&state, format!("📢 {message}"), true);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// to a single client, not part of the ordered stream (id 0)
+fn notice(body: String) -> ChatMessage {
+    ChatMessage {
+        id: 0,
+        ts: Utc::now(),
+        body,
+        kind: None,
+    }
+}
+
+fn to_frame(msg: &ChatMessage) -> Message {
+    Message::Text(serde_json::to_string(msg).unwrap())
+}
+
+// send to all connected clients, optionally remember for clients joining later
+// the id is assigned while holding the recent_messages lock, this keeps the
+// id, broadcast and replay order the same.
+fn broadcast(state: &AppState, body: String, remember: bool) {
+    let mut recent_messages = state.recent_messages.lock().unwrap();
+    let msg = ChatMessage {
+        id: state.next_message_id.fetch_add(1, Ordering::SeqCst),
+        ts: Utc::now(),
+        body,
+        kind: None,
+    };
+    if remember {
+        remember_message(&mut recent_messages, msg.clone());
+    }
+    // only fails if there are no receivers
+    let _ = state.tx.send(msg);
+}
+
+fn remember_message(recent_messages: &mut VecDeque<ChatMessage>, msg: ChatMessage) {
+    if recent_messages.len() >= RECENT_MESSAGES_MAX {
+        recent_messages.pop_front();
+    }
+    recent_messages.push_back(msg);
+}
+
+// graceful shutdown, after the listener stopped accepting connections:
+// every connection sends a server_shutdown frame and closes with 1012 (service
+// restart), then the process has SHUTDOWN_DRAIN to flush them before it exits.
+// Clients reconnect with ?since after a moment, e.g. to the next machine of a
+// rolling deploy. Not remembered, it isn't replayed after the restart.
+pub async fn notify_shutdown(state: &AppState) {
+    // including silent connections
+    let connected = state.chat_connections.load(Ordering::SeqCst);
+    if connected == 0 {
+        return;
+    }
+    info!("Notifying {connected} chat connections of the shutdown");
+    {
+        let _recent_messages = state.recent_messages.lock().unwrap();
+        let msg = ChatMessage {
+            id: state.next_message_id.fetch_add(1, Ordering::SeqCst),
+            ts: Utc::now(),
+            body: "🔄 Server restarting, reconnecting in a moment.".to_string(),
+            kind: Some(SERVER_SHUTDOWN),
+        };
+        let _ = state.tx.send(msg);
+    }
+    tokio::time::sleep(SHUTDOWN_DRAIN).await;
+}
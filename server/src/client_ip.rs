@@ -0,0 +1,48 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::state::AppState;
+
+// ip of the client
+// behind a proxy (fly) the socket peer is the proxy, the client ip is in a
+// header set by it (TRUSTED_IP_HEADER, default Fly-Client-IP). The header is
+// only trusted with TRUST_PROXY=true, otherwise anyone could spoof it.
+// falls back to the socket peer (ConnectInfo), None if neither is available
+// (e.g. in-process without connect info).
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let from_header = parts
+            .extensions
+            .get::<AppState>()
+            .filter(|app_state| app_state.config.trust_proxy)
+            .and_then(|app_state| parts.headers.get(&app_state.config.trusted_ip_header))
+            .and_then(|v| v.to_str().ok())
+            // X-Forwarded-For: the last entry is the one added by the proxy
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+
+        let from_socket = || {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        };
+
+        Ok(ClientIp(from_header.or_else(from_socket)))
+    }
+}
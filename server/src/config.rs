@@ -0,0 +1,311 @@
+use std::{env, net::SocketAddr, path::PathBuf, str::FromStr};
+use tokio::time::Duration;
+use uuid::Uuid;
+use webauthn_rs::prelude::{COSEAlgorithm, Url};
+
+/*
+ * typed config, read from env vars (and .env) once at startup
+ * invalid or missing values fail fast at boot, naming the env var.
+ */
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub log_format: LogFormat,
+    // one log line per request, see with_access_log in main.rs
+    pub http_access_log: bool,
+    pub listen_addr: SocketAddr,
+    // second listener for the operator endpoints (/debug, /admin/*), they
+    // aren't served on listen_addr then, see admin_router in main.rs
+    pub admin_listen_addr: Option<SocketAddr>,
+    pub database_url: String,
+    // relative database paths are resolved in it, e.g. a volume mount
+    pub data_dir: Option<PathBuf>,
+    // migrations read from this directory at startup instead of the embedded
+    // ones, see migrations_from_dir in db.rs
+    pub migrations_dir: Option<PathBuf>,
+    pub db_slow_call_threshold: Duration,
+    pub db_max_concurrent_calls: usize,
+    // db calls fail (503) after this long, see DB::timed_call_with_timeout.
+    // admin: admin endpoints and commands, background cleanup
+    pub db_call_timeout: Duration,
+    pub db_admin_call_timeout: Duration,
+    // Effective domain name. If changed, all credentials are invalidated!!
+    pub rp_id: String,
+    // Url containing the effective domain name, MUST include the port number!
+    pub rp_origin: Url,
+    // refuse to start with an insecure RP_ORIGIN instead of warning, see AppState::new
+    pub rp_origin_strict: bool,
+    pub rp_name: String,
+    // credential algorithms allowed at registration, es256 and rs256 (default).
+    // es256 only is stricter, but excludes older authenticators (e.g. windows
+    // hello) which only support rs256. Changing it only affects new registrations.
+    pub webauthn_algorithms: Vec<COSEAlgorithm>,
+    pub session_name: String,
+    // prepended to the session keys, see Config::session_key
+    pub session_key_prefix: String,
+    pub cookies_secure: bool,
+    // Domain of the session and informative cookies, e.g. example.com to share
+    // them with the subdomains. Host only if not set.
+    pub cookie_domain: Option<String>,
+    // trust the client ip header of the proxy, see ClientIp
+    pub trust_proxy: bool,
+    pub trusted_ip_header: String,
+    // js readable cookie informing the client about the signed in user
+    pub info_cookie_enabled: bool,
+    pub info_cookie_contents: InfoCookieContents,
+    pub session_cleanup_interval: Duration,
+    // random delay (up to) before the first and added to each sweep, for
+    // instances sharing a store, see continuously_delete_expired. 0: off
+    pub session_cleanup_jitter: Duration,
+    // client origins allowed to call the api (split origin deployments),
+    // empty: no cors headers, see cors_layer in main.rs
+    pub cors_allowed_origins: Vec<String>,
+    // how long browsers may cache a preflight response
+    pub cors_max_age: Duration,
+    // allow letters and digits of all scripts in usernames, not only ascii
+    pub username_allow_unicode: bool,
+    // reject registrations without `User-Agent` header, see ExtractUserAgent
+    pub require_user_agent: bool,
+    // lowercase, can't be registered by users (impersonation), see start_register
+    pub reserved_usernames: Vec<String>,
+    pub max_authenticators_per_user: usize,
+    // proof of work before registering a new user, see register_challenge
+    pub pow_registration: bool,
+    // leading zero bits of the hash, each one doubles the client's work
+    pub pow_difficulty: u32,
+    // delete users (admin delete-user, merge) instead of setting deleted_at
+    pub hard_delete: bool,
+    #[cfg(feature = "tls")]
+    pub tls_cert_path: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_key_path: Option<String>,
+    // slots of the chat broadcast channel, see backpressure in chat.rs
+    #[cfg(feature = "chat")]
+    pub chat_broadcast_capacity: usize,
+    // open chat websockets (all users), further upgrades get a 503
+    #[cfg(feature = "chat")]
+    pub chat_max_connections: usize,
+    // messages queued per connection for a slow socket, see backpressure in chat.rs
+    #[cfg(feature = "chat")]
+    pub chat_send_buffer: usize,
+    // accounts younger than this can't join the chat (throwaway spam), 0: off
+    #[cfg(feature = "chat")]
+    pub chat_min_account_age: Duration,
+    // bearer token of the admin endpoints, disabled if not set, see ExtractAdmin
+    pub admin_token: Option<String>,
+    // static bearer token acting as the user API_TOKEN_USER_ID (server to
+    // server, cli), disabled if not set, see ExtractMeOrToken
+    pub api_token: Option<(String, Uuid)>,
+    // set by fly.io, empty if not running there
+    pub fly_machine_id: String,
+    pub fly_region: String,
+    pub primary_region: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            log_format: parse("LOG_FORMAT", "pretty"),
+            http_access_log: parse("HTTP_ACCESS_LOG", "false"),
+            listen_addr: parse("LISTEN_HOST_PORT", "0.0.0.0:3000"),
+            admin_listen_addr: env::var("ADMIN_LISTEN_HOST_PORT").ok().map(|addr| {
+                addr.parse()
+                    .expect("Invalid ADMIN_LISTEN_HOST_PORT environment variable")
+            }),
+            database_url: optional("DATABASE_URL", "sqlite://sqlite.db"),
+            data_dir: env::var("DATA_DIR").ok().map(PathBuf::from),
+            migrations_dir: env::var("MIGRATIONS_DIR")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from),
+            db_slow_call_threshold: Duration::from_millis(parse(
+                "DB_SLOW_CALL_THRESHOLD_MS",
+                "100",
+            )),
+            db_max_concurrent_calls: parse("DB_MAX_CONCURRENT_CALLS", "64"),
+            db_call_timeout: Duration::from_millis(parse("DB_CALL_TIMEOUT_MS", "2000")),
+            db_admin_call_timeout: Duration::from_millis(parse(
+                "DB_ADMIN_CALL_TIMEOUT_MS",
+                "30000",
+            )),
+            rp_id: required("RP_ID"),
+            rp_origin: Url::parse(&required("RP_ORIGIN"))
+                .expect("Invalid RP_ORIGIN environment variable"),
+            rp_origin_strict: parse("RP_ORIGIN_STRICT", "false"),
+            rp_name: required("RP_NAME"),
+            webauthn_algorithms: algorithms("WEBAUTHN_ALGORITHMS", "es256,rs256"),
+            session_name: optional("SESSION_NAME", "session"),
+            session_key_prefix: optional("SESSION_KEY_PREFIX", ""),
+            cookies_secure: optional("COOKIES_SECURE", "true") != "false",
+            cookie_domain: None,
+            trust_proxy: parse("TRUST_PROXY", "false"),
+            trusted_ip_header: optional("TRUSTED_IP_HEADER", "Fly-Client-IP"),
+            info_cookie_enabled: !parse::<bool>("DISABLE_INFO_COOKIE", "false"),
+            info_cookie_contents: parse("INFO_COOKIE_CONTENTS", "full"),
+            session_cleanup_interval: Duration::from_secs(parse(
+                "SESSION_CLEANUP_INTERVAL_SECONDS",
+                "50",
+            )),
+            session_cleanup_jitter: Duration::from_secs(parse(
+                "SESSION_CLEANUP_JITTER_SECONDS",
+                "0",
+            )),
+            cors_allowed_origins: optional("CORS_ALLOWED_ORIGINS", "")
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            cors_max_age: Duration::from_secs(parse("CORS_MAX_AGE_SECONDS", "600")),
+            username_allow_unicode: parse("USERNAME_ALLOW_UNICODE", "false"),
+            require_user_agent: parse("REQUIRE_USER_AGENT", "true"),
+            reserved_usernames: optional(
+                "RESERVED_USERNAMES",
+                "admin,administrator,root,system,support,help,security,moderator,staff",
+            )
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect(),
+            max_authenticators_per_user: parse("MAX_AUTHENTICATORS_PER_USER", "20"),
+            pow_registration: parse("POW_REGISTRATION", "false"),
+            pow_difficulty: parse("POW_DIFFICULTY", "20"),
+            hard_delete: parse("HARD_DELETE", "false"),
+            #[cfg(feature = "tls")]
+            tls_cert_path: env::var("TLS_CERT_PATH").ok(),
+            #[cfg(feature = "tls")]
+            tls_key_path: env::var("TLS_KEY_PATH").ok(),
+            #[cfg(feature = "chat")]
+            chat_broadcast_capacity: parse("CHAT_BROADCAST_CAPACITY", "100"),
+            #[cfg(feature = "chat")]
+            chat_max_connections: parse("CHAT_MAX_CONNECTIONS", "1000"),
+            #[cfg(feature = "chat")]
+            chat_send_buffer: parse("CHAT_SEND_BUFFER", "64"),
+            #[cfg(feature = "chat")]
+            chat_min_account_age: Duration::from_secs(
+                60 * parse::<u64>("CHAT_MIN_ACCOUNT_AGE_MINUTES", "0"),
+            ),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            api_token: api_token(),
+            fly_machine_id: optional("FLY_MACHINE_ID", ""),
+            fly_region: optional("FLY_REGION", ""),
+            primary_region: optional("PRIMARY_REGION", ""),
+        }
+        .with_cookie_domain()
+    }
+
+    // COOKIE_DOMAIN must be the host of RP_ORIGIN or a parent of it, browsers
+    // would reject the cookies otherwise (sign in would silently not stick)
+    fn with_cookie_domain(mut self) -> Self {
+        let Some(domain) = env::var("COOKIE_DOMAIN").ok().filter(|d| !d.is_empty()) else {
+            return self;
+        };
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        let host = self.rp_origin.host_str().unwrap_or("");
+        if host != domain && !host.ends_with(&format!(".{domain}")) {
+            panic!(
+                "Invalid COOKIE_DOMAIN environment variable, {domain} is not a parent of {host}"
+            );
+        }
+        self.cookie_domain = Some(domain);
+        self
+    }
+
+    // key in the session, e.g. "myapp:reg_state" with SESSION_KEY_PREFIX=myapp:
+    // so the keys don't collide with the ones of a host app sharing the store
+    pub fn session_key(&self, key: &str) -> String {
+        format!("{}{key}", self.session_key_prefix)
+    }
+}
+
+// pretty: human readable (default), json: one object per line, e.g. for
+// log aggregation. Filtered by RUST_LOG in both cases.
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+// full: user and expiry date (default), expiry_only: no user (username) in
+// the js readable cookie, the client gets it from /me.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InfoCookieContents {
+    Full,
+    ExpiryOnly,
+}
+
+impl FromStr for InfoCookieContents {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(InfoCookieContents::Full),
+            "expiry_only" => Ok(InfoCookieContents::ExpiryOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+fn required(key: &str) -> String {
+    env::var(key).unwrap_or_else(|_| panic!("{key} environment variable not set"))
+}
+
+fn optional(key: &str, default: &str) -> String {
+    env::var(key).unwrap_or(default.to_string())
+}
+
+fn parse<T: FromStr>(key: &str, default: &str) -> T {
+    optional(key, default)
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid {key} environment variable"))
+}
+
+// API_TOKEN and API_TOKEN_USER_ID, only both together
+fn api_token() -> Option<(String, Uuid)> {
+    let token = env::var("API_TOKEN").ok().filter(|t| !t.is_empty())?;
+    let user_id = Uuid::parse_str(&required("API_TOKEN_USER_ID"))
+        .expect("Invalid API_TOKEN_USER_ID environment variable");
+    Some((token, user_id))
+}
+
+// comma separated list, subset of the algorithms passkey registrations offer
+fn algorithms(key: &str, default: &str) -> Vec<COSEAlgorithm> {
+    let algorithms: Vec<COSEAlgorithm> = optional(key, default)
+        .split(',')
+        .map(|name| match name.trim().to_lowercase().as_str() {
+            "es256" => COSEAlgorithm::ES256,
+            "rs256" => COSEAlgorithm::RS256,
+            _ => panic!("Invalid {key} environment variable, unknown algorithm {name}"),
+        })
+        .collect();
+    if algorithms.is_empty() {
+        panic!("Invalid {key} environment variable, no algorithm");
+    }
+    algorithms
+}
+
+// the defaults plus the required RP_* values and an in memory db, for unit tests
+#[cfg(test)]
+pub fn test_config() -> Config {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        env::set_var("RP_ID", "localhost");
+        env::set_var("RP_ORIGIN", "http://localhost:3000");
+        env::set_var("RP_NAME", "test");
+    });
+    Config {
+        database_url: "sqlite://:memory:".to_string(),
+        ..Config::from_env()
+    }
+}
@@ -1,15 +1,74 @@
 use include_dir::{include_dir, Dir};
 use lazy_static::lazy_static;
-use rusqlite_migration::AsyncMigrations;
+use rusqlite::OpenFlags;
+use rusqlite_migration::{AsyncMigrations, M};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::Semaphore;
 use tokio_rusqlite::Connection;
 
+use crate::config::Config;
+
 //use crate::store::Store;
 
 #[derive(Clone)]
 pub struct DB {
     pub conn: Connection,
+    // calls taking longer are logged, see timed_call
+    pub slow_call_threshold: Duration,
+    // calls taking longer fail with DbTimeout, see timed_call_with_timeout
+    pub call_timeout: Duration,
+    // for slow, legitimate calls (admin, background)
+    pub admin_call_timeout: Duration,
+    // bounds the calls running or queued on the connection, see timed_call
+    permits: Arc<Semaphore>,
+    // one background call at a time, see background_call
+    background_permit: Arc<Semaphore>,
+    rejected_calls: Arc<AtomicU64>,
+}
+
+// returned by timed_call if DB_MAX_CONCURRENT_CALLS calls are in flight
+#[derive(Debug, thiserror::Error)]
+#[error("Too many concurrent db calls")]
+pub struct DbBusy;
+
+pub fn is_busy(e: &tokio_rusqlite::Error) -> bool {
+    matches!(e, tokio_rusqlite::Error::Other(inner) if inner.is::<DbBusy>())
 }
 
+// returned by timed_call_with_timeout if the call (queued or running) takes
+// longer than its timeout
+#[derive(Debug, thiserror::Error)]
+#[error("DB call timed out")]
+pub struct DbTimeout;
+
+pub fn is_timeout(e: &tokio_rusqlite::Error) -> bool {
+    matches!(e, tokio_rusqlite::Error::Other(inner) if inner.is::<DbTimeout>())
+}
+
+// SQLITE_BUSY or SQLITE_LOCKED, e.g. write contention with another process on
+// the same file (admin commands, backup tools). Transient, see timed_call_retry.
+pub fn is_locked(e: &tokio_rusqlite::Error) -> bool {
+    matches!(
+        e,
+        tokio_rusqlite::Error::Rusqlite(rusqlite::Error::SqliteFailure(err, _))
+            if matches!(
+                err.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            )
+    )
+}
+
+// attempts of timed_call_retry, including the first
+const LOCKED_RETRY_ATTEMPTS: u32 = 3;
+
 static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
 // Define migrations. These are applied atomically.
@@ -18,12 +77,104 @@ lazy_static! {
         AsyncMigrations::from_directory(&MIGRATIONS_DIR).unwrap();
 }
 
+// migrations of MIGRATIONS_DIR, read at startup instead of the embedded ones,
+// e.g. an emergency migration without a rebuild. Same layout as
+// server/migrations: <id>-<name>/up.sql (and optional down.sql), ids 1, 2, ...
+// without gaps. It must contain all migrations, not only the new ones.
+// An invalid directory fails at boot. The sql is leaked, it lives as long as
+// the process anyway.
+fn migrations_from_dir(dir: &Path) -> AsyncMigrations {
+    let entries = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Invalid MIGRATIONS_DIR {}: {e}", dir.display()));
+    let mut migrations: Vec<(usize, PathBuf)> = entries
+        .map(|entry| {
+            let path = entry
+                .unwrap_or_else(|e| panic!("Invalid MIGRATIONS_DIR {}: {e}", dir.display()))
+                .path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('-'))
+                .and_then(|(id, _)| id.parse::<usize>().ok())
+                .filter(|_| path.is_dir())
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Invalid MIGRATIONS_DIR, {} is not a <id>-<name> directory",
+                        path.display()
+                    )
+                });
+            (id, path)
+        })
+        .collect();
+    migrations.sort();
+
+    let read = |path: PathBuf| -> Option<&'static str> {
+        let sql = fs::read_to_string(&path).ok()?;
+        Some(Box::leak(sql.into_boxed_str()))
+    };
+    let migrations = migrations
+        .into_iter()
+        .enumerate()
+        .map(|(i, (id, path))| {
+            if id != i + 1 {
+                panic!(
+                    "Invalid MIGRATIONS_DIR, expected migration {} but found {}",
+                    i + 1,
+                    path.display()
+                );
+            }
+            let up = read(path.join("up.sql")).unwrap_or_else(|| {
+                panic!("Invalid MIGRATIONS_DIR, no up.sql in {}", path.display())
+            });
+            let migration = M::up(up);
+            match read(path.join("down.sql")) {
+                Some(down) => migration.down(down),
+                None => migration,
+            }
+        })
+        .collect::<Vec<_>>();
+    if migrations.is_empty() {
+        panic!("Invalid MIGRATIONS_DIR {}, no migrations", dir.display());
+    }
+    info!(
+        "Using {} migrations of MIGRATIONS_DIR {} instead of the embedded ones",
+        migrations.len(),
+        dir.display()
+    );
+    AsyncMigrations::new(migrations)
+}
+
 impl DB {
-    pub async fn new() -> Self {
-        let db_url = std::env::var("DATABASE_URL").unwrap();
-        let db_path = db_url.split("://").collect::<Vec<&str>>()[1];
+    pub async fn new(config: &Config) -> Self {
+        // loaded first, so an invalid MIGRATIONS_DIR fails even if unused (read only)
+        let external_migrations = config.migrations_dir.as_deref().map(migrations_from_dir);
 
-        let mut conn = Connection::open(&db_path).await.unwrap();
+        let (db_path, flags) = parse_database_url(&config.database_url, config.data_dir.as_deref());
+        let read_only = flags.contains(OpenFlags::SQLITE_OPEN_READ_ONLY);
+        let in_memory = flags.contains(OpenFlags::SQLITE_OPEN_MEMORY);
+
+        // e.g. a fresh volume mount
+        let parent = db_path.parent().filter(|p| !p.as_os_str().is_empty());
+        if let Some(parent) = parent.filter(|_| !read_only && !in_memory) {
+            fs::create_dir_all(parent).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to create database directory {}: {e}",
+                    parent.display()
+                )
+            });
+        }
+
+        let mut conn = Connection::open_with_flags(&db_path, flags)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to open database {}: {e}", db_path.display()));
+
+        // a read only database (replica) is expected to be migrated and in wal
+        // mode already, every write (sign in, sessions) fails
+        if read_only {
+            warn!("Database {} opened read only", db_path.display());
+            info!("DB ready");
+            return Self::with_conn(conn, config);
+        }
 
         conn.call(move |conn| {
             conn.execute_batch(
@@ -40,14 +191,278 @@ impl DB {
 
         // Update the database schema, atomically
         info!("Applying migrations...");
-        MIGRATIONS
+        external_migrations
+            .as_ref()
+            .unwrap_or(&MIGRATIONS)
             .to_latest(&mut conn)
             .await
             .expect("Failed to apply migrations");
 
         info!("DB ready");
 
+        Self::with_conn(conn, config)
+    }
+
+    fn with_conn(conn: Connection, config: &Config) -> Self {
         //let store = Store::new(conn.clone()).await;
-        Self { conn } //, store }
+        Self {
+            conn,
+            slow_call_threshold: config.db_slow_call_threshold,
+            call_timeout: config.db_call_timeout,
+            admin_call_timeout: config.db_admin_call_timeout,
+            permits: Arc::new(Semaphore::new(config.db_max_concurrent_calls)),
+            background_permit: Arc::new(Semaphore::new(1)),
+            rejected_calls: Arc::new(AtomicU64::new(0)),
+        } //, store }
+    }
+
+    // all queries run on the single worker thread of the connection, one slow
+    // call stalls all others. Warn if a call (including the time queued behind
+    // other calls) takes longer than DB_SLOW_CALL_THRESHOLD_MS.
+    //
+    // at most DB_MAX_CONCURRENT_CALLS calls run or wait for the connection,
+    // further calls fail right away with DbBusy (503 for clients). Unbounded
+    // queuing doesn't make the single thread faster: under a burst every call
+    // waits behind all others, latency grows for everyone until requests time
+    // out anyway, after the work was done. Rejecting early keeps the latency of
+    // the accepted calls bounded and tells clients to retry.
+    // (the session store uses the connection directly and isn't bounded)
+    //
+    // fails with DbTimeout after DB_CALL_TIMEOUT_MS, for the auth hot path.
+    pub async fn timed_call<F, R>(
+        &self,
+        name: &'static str,
+        function: F,
+    ) -> tokio_rusqlite::Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> tokio_rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.timed_call_with_timeout(name, self.call_timeout, function)
+            .await
+    }
+
+    // timed_call with the timeout of this call, e.g. admin_call_timeout for
+    // slow admin queries. The timeout includes the time queued behind other
+    // calls. The caller gets DbTimeout (503 for clients), but sqlite can't be
+    // interrupted per call: a started query still runs to completion on the
+    // connection thread, only nobody waits for it.
+    pub async fn timed_call_with_timeout<F, R>(
+        &self,
+        name: &'static str,
+        timeout: Duration,
+        function: F,
+    ) -> tokio_rusqlite::Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> tokio_rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let _permit = self.permits.try_acquire().map_err(|_| {
+            let rejected = self.rejected_calls.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                db_call = name,
+                rejected_total = rejected,
+                "DB saturated, rejecting call"
+            );
+            tokio_rusqlite::Error::Other(Box::new(DbBusy))
+        })?;
+
+        let start = Instant::now();
+        let res = tokio::time::timeout(timeout, self.conn.call(function))
+            .await
+            .unwrap_or_else(|_| {
+                warn!(
+                    db_call = name,
+                    timeout_ms = timeout.as_millis() as u64,
+                    "DB call timed out"
+                );
+                Err(tokio_rusqlite::Error::Other(Box::new(DbTimeout)))
+            });
+        let elapsed = start.elapsed();
+        if elapsed > self.slow_call_threshold {
+            warn!(
+                db_call = name,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow db call"
+            );
+        }
+        res
+    }
+
+    // for calls nobody is waiting for (cleanup, admin listings), timed_call is
+    // for interactive ones (auth, /me). With admin_call_timeout.
+    // the connection runs calls in order on its single thread, it has no
+    // priorities. Background calls queue here instead, at most one of them is
+    // on the connection at a time, so an interactive call waits behind at most
+    // one background call. Long background work must be split into short calls
+    // (e.g. chunks, see continuously_delete_expired) so interactive calls can
+    // run in between.
+    pub async fn background_call<F, R>(
+        &self,
+        name: &'static str,
+        function: F,
+    ) -> tokio_rusqlite::Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> tokio_rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let _background_permit = self
+            .background_permit
+            .acquire()
+            .await
+            .expect("background_permit is never closed");
+        self.timed_call_with_timeout(name, self.admin_call_timeout, function)
+            .await
+    }
+
+    // timed_call for writes, retried with jittered backoff (~25ms, ~50ms) if
+    // the database is locked (see is_locked). Other errors (constraints, logic)
+    // are returned right away. The function runs again, so it must be
+    // idempotent on failure, e.g. a transaction (rolled back if dropped).
+    pub async fn timed_call_retry<F, R>(
+        &self,
+        name: &'static str,
+        function: F,
+    ) -> tokio_rusqlite::Result<R>
+    where
+        F: Fn(&mut rusqlite::Connection) -> tokio_rusqlite::Result<R> + Clone + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.timed_call(name, function.clone()).await {
+                Err(e) if is_locked(&e) && attempt < LOCKED_RETRY_ATTEMPTS => {
+                    let backoff = Duration::from_millis(25 * 2u64.pow(attempt - 1) + jitter_ms());
+                    warn!(
+                        db_call = name,
+                        attempt,
+                        backoff_ms = backoff.as_millis() as u64,
+                        "DB locked, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// 0-9ms, so concurrent retries don't collide again
+fn jitter_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| (d.subsec_nanos() % 10) as u64)
+}
+
+// path of the sqlite file and open flags of DATABASE_URL (sqlite://<path>?<params>)
+// relative paths are resolved in DATA_DIR if set, absolute paths, :memory: and
+// mode=memory are used as is.
+// params, like the sqlite uri filenames:
+//   mode=ro|rw|rwc|memory  read only, read write, create if missing (default),
+//                          in memory (named, shared with cache=shared)
+//   cache=shared|private
+// unknown params are ignored with a warning, invalid values fail at boot.
+fn parse_database_url(db_url: &str, data_dir: Option<&Path>) -> (PathBuf, OpenFlags) {
+    let (_, path) = db_url
+        .split_once("://")
+        .expect("Invalid DATABASE_URL, expected sqlite://<path>");
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+    let mut flags = OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let mut mode = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match (key, value) {
+            ("mode", "ro") => mode = OpenFlags::SQLITE_OPEN_READ_ONLY,
+            ("mode", "rw") => mode = OpenFlags::SQLITE_OPEN_READ_WRITE,
+            ("mode", "rwc") => {
+                mode = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+            }
+            ("mode", "memory") => {
+                mode = OpenFlags::SQLITE_OPEN_READ_WRITE
+                    | OpenFlags::SQLITE_OPEN_CREATE
+                    | OpenFlags::SQLITE_OPEN_MEMORY
+            }
+            ("cache", "shared") => flags |= OpenFlags::SQLITE_OPEN_SHARED_CACHE,
+            ("cache", "private") => flags |= OpenFlags::SQLITE_OPEN_PRIVATE_CACHE,
+            ("mode", _) | ("cache", _) => {
+                panic!("Invalid DATABASE_URL, unknown {key} {value}")
+            }
+            _ => warn!("Ignoring unknown DATABASE_URL parameter {key}"),
+        }
+    }
+    flags |= mode;
+
+    let in_memory = path == ":memory:" || mode.contains(OpenFlags::SQLITE_OPEN_MEMORY);
+    let path = match data_dir {
+        Some(data_dir) if !in_memory => data_dir.join(path),
+        _ => PathBuf::from(path),
+    };
+    (path, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::sync::atomic::AtomicU32;
+
+    async fn test_db() -> DB {
+        DB::new(&test_config()).await
+    }
+
+    fn busy() -> tokio_rusqlite::Error {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            Some("database is locked".to_string()),
+        )
+        .into()
+    }
+
+    // fails with the error of the attempt until it returns None
+    async fn call_retry(
+        db: &DB,
+        error: impl Fn(u32) -> Option<tokio_rusqlite::Error> + Clone + Send + Sync + 'static,
+    ) -> (tokio_rusqlite::Result<u32>, u32) {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let res = db
+            .timed_call_retry("test", {
+                let attempts = attempts.clone();
+                move |_conn| {
+                    let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+                    match error(attempt) {
+                        Some(e) => Err(e),
+                        None => Ok(attempt),
+                    }
+                }
+            })
+            .await;
+        (res, attempts.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn retry_succeeds_after_busy() {
+        let db = test_db().await;
+        let (res, attempts) = call_retry(&db, |attempt| (attempt == 1).then(busy)).await;
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_the_last_attempt() {
+        let db = test_db().await;
+        let (res, attempts) = call_retry(&db, |_| Some(busy())).await;
+        assert!(is_locked(&res.unwrap_err()));
+        assert_eq!(attempts, LOCKED_RETRY_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn logic_errors_are_not_retried() {
+        let db = test_db().await;
+        let (res, attempts) =
+            call_retry(&db, |_| Some(rusqlite::Error::QueryReturnedNoRows.into())).await;
+        assert!(!is_locked(&res.unwrap_err()));
+        assert_eq!(attempts, 1);
     }
 }
@@ -4,6 +4,9 @@ use axum::{
 };
 use thiserror::Error;
 
+// stable, machine readable error code of rest responses, see WebauthnError::code
+const ERROR_CODE_HEADER: &str = "x-error-code";
+
 #[derive(Error, Debug)]
 pub enum WebauthnError {
     #[error("unknown webauthn error")]
@@ -16,6 +19,8 @@ pub enum WebauthnError {
     InvalidSessionState(#[from] tower_sessions::session::Error),
     #[error("Username must be between 3 and 24 characters")]
     InvalidUsername,
+    #[error("Username may only contain letters, digits, _, - and .")]
+    InvalidUsernameCharacters,
     #[error("UserID and credentialID don't match")]
     UserAndCredentialDontMatch,
     #[error("Username already exists. Please login or choose a different username.")]
@@ -26,15 +31,145 @@ pub enum WebauthnError {
     RegisterForSelfOnly,
     #[error("You are already signed in.")]
     AlreadySignedIn,
+    #[error("Registration was restarted, e.g. in another tab. Please try again.")]
+    RegistrationReplaced,
+    #[error("This credential already belongs to your account.")]
+    MergeWithSelf,
+    #[error("The algorithm of this authenticator is not allowed.")]
+    AlgorithmNotAllowed,
+    #[error("This username is reserved. Please choose a different username.")]
+    UsernameReserved,
+    #[error("You have reached the maximum number of credentials.")]
+    TooManyAuthenticators,
+    #[error("Too many requests, please try again.")]
+    DatabaseBusy,
+    #[error("Invalid request body.")]
+    InvalidRequest,
+    #[error("Nickname must be between 1 and 64 characters.")]
+    InvalidNickname,
+    #[error("Token name must be between 1 and 64 characters.")]
+    InvalidTokenName,
+    #[error("Token not found.")]
+    TokenNotFound,
+    #[error("This passkey was created for a different site.")]
+    OriginMismatch,
+    #[error("The account of this passkey no longer exists. Please register again.")]
+    AccountNoLongerExists,
+    #[error("Please confirm with your passkey first.")]
+    ReauthRequired,
+    #[error("This passkey belongs to a different account.")]
+    ReauthOtherUser,
+    #[error("Please solve the registration challenge first.")]
+    ProofOfWorkRequired,
+    #[error("The solution of the registration challenge is invalid.")]
+    ProofOfWorkInvalid,
 }
+impl WebauthnError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            // distinct from invalid usernames, so the signup form can show "taken"
+            WebauthnError::UsernameAlreadyExists => StatusCode::CONFLICT,
+            // shown like a taken username
+            WebauthnError::UsernameReserved => StatusCode::CONFLICT,
+            // client conditions, not server faults
+            WebauthnError::InvalidUsername => StatusCode::BAD_REQUEST,
+            WebauthnError::InvalidUsernameCharacters => StatusCode::BAD_REQUEST,
+            WebauthnError::AlgorithmNotAllowed => StatusCode::BAD_REQUEST,
+            // the limit of MAX_AUTHENTICATORS_PER_USER, see start_register and
+            // finish_register
+            WebauthnError::TooManyAuthenticators => StatusCode::CONFLICT,
+            // a newer registration (e.g. another tab) owns the reg_state
+            WebauthnError::RegistrationReplaced => StatusCode::CONFLICT,
+            WebauthnError::MergeWithSelf => StatusCode::CONFLICT,
+            // shed load, see DB::timed_call
+            WebauthnError::DatabaseBusy => StatusCode::SERVICE_UNAVAILABLE,
+            // malformed body, see Payload
+            WebauthnError::InvalidRequest => StatusCode::BAD_REQUEST,
+            WebauthnError::InvalidNickname => StatusCode::BAD_REQUEST,
+            WebauthnError::InvalidTokenName => StatusCode::BAD_REQUEST,
+            WebauthnError::TokenNotFound => StatusCode::NOT_FOUND,
+            // see ceremony_error in auth.rs
+            WebauthnError::OriginMismatch => StatusCode::BAD_REQUEST,
+            // identify_discoverable_authentication of a malformed assertion
+            WebauthnError::UserAndCredentialDontMatch => StatusCode::BAD_REQUEST,
+            // a stale passkey after the account was deleted
+            WebauthnError::AccountNoLongerExists => StatusCode::UNAUTHORIZED,
+            // see ExtractReauth
+            WebauthnError::ReauthRequired => StatusCode::FORBIDDEN,
+            WebauthnError::ReauthOtherUser => StatusCode::FORBIDDEN,
+            // POW_REGISTRATION, see register_challenge
+            WebauthnError::ProofOfWorkRequired => StatusCode::PRECONDITION_REQUIRED,
+            WebauthnError::ProofOfWorkInvalid => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // errors of DB::timed_call: 503 if the db is saturated, 500 otherwise
+    pub fn from_db(e: &tokio_rusqlite::Error) -> Self {
+        // locked: still locked after the retries of timed_call_retry
+        if crate::db::is_busy(e) || crate::db::is_locked(e) || crate::db::is_timeout(e) {
+            WebauthnError::DatabaseBusy
+        } else {
+            WebauthnError::GenericDatabaseError
+        }
+    }
+
+    // stable code shared by rest (X-Error-Code header) and graphql
+    // (extensions.code), the messages may change, the codes don't.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WebauthnError::Unknown => "UNKNOWN",
+            WebauthnError::CorruptSession => "CORRUPT_SESSION",
+            WebauthnError::UserNotFound => "USER_NOT_FOUND",
+            WebauthnError::InvalidSessionState(_) => "INVALID_SESSION_STATE",
+            WebauthnError::InvalidUsername => "INVALID_USERNAME",
+            WebauthnError::InvalidUsernameCharacters => "INVALID_USERNAME_CHARACTERS",
+            WebauthnError::UserAndCredentialDontMatch => "USER_AND_CREDENTIAL_DONT_MATCH",
+            WebauthnError::UsernameAlreadyExists => "USERNAME_ALREADY_EXISTS",
+            WebauthnError::GenericDatabaseError => "DATABASE_ERROR",
+            WebauthnError::RegisterForSelfOnly => "REGISTER_FOR_SELF_ONLY",
+            WebauthnError::AlreadySignedIn => "ALREADY_SIGNED_IN",
+            WebauthnError::RegistrationReplaced => "REGISTRATION_REPLACED",
+            WebauthnError::MergeWithSelf => "MERGE_WITH_SELF",
+            WebauthnError::AlgorithmNotAllowed => "ALGORITHM_NOT_ALLOWED",
+            WebauthnError::UsernameReserved => "USERNAME_RESERVED",
+            WebauthnError::TooManyAuthenticators => "TOO_MANY_AUTHENTICATORS",
+            WebauthnError::DatabaseBusy => "DATABASE_BUSY",
+            WebauthnError::InvalidRequest => "INVALID_REQUEST",
+            WebauthnError::InvalidNickname => "INVALID_NICKNAME",
+            WebauthnError::InvalidTokenName => "INVALID_TOKEN_NAME",
+            WebauthnError::TokenNotFound => "TOKEN_NOT_FOUND",
+            WebauthnError::OriginMismatch => "ORIGIN_MISMATCH",
+            WebauthnError::AccountNoLongerExists => "ACCOUNT_NO_LONGER_EXISTS",
+            WebauthnError::ReauthRequired => "REAUTH_REQUIRED",
+            WebauthnError::ReauthOtherUser => "REAUTH_OTHER_USER",
+            WebauthnError::ProofOfWorkRequired => "PROOF_OF_WORK_REQUIRED",
+            WebauthnError::ProofOfWorkInvalid => "PROOF_OF_WORK_INVALID",
+        }
+    }
+}
+
+// graphql resolvers: `.map_err(|e| e.extend())` adds extensions.code
+#[cfg(feature = "graphql")]
+impl async_graphql::ErrorExtensions for WebauthnError {
+    fn extend(&self) -> async_graphql::Error {
+        async_graphql::Error::new(self.to_string()).extend_with(|_, e| e.set("code", self.code()))
+    }
+}
+
 impl IntoResponse for WebauthnError {
     fn into_response(self) -> Response {
+        let status = self.status_code();
+        let code = self.code();
         let body = match self {
             WebauthnError::CorruptSession => "Corrupt Session",
             WebauthnError::UserNotFound => "User Not Found",
             WebauthnError::Unknown => "Unknown Error",
             WebauthnError::InvalidSessionState(_) => "Deserialising Session failed",
             WebauthnError::InvalidUsername => "Username must be between 3 and 24 characters",
+            WebauthnError::InvalidUsernameCharacters => {
+                "Username may only contain letters, digits, _, - and ."
+            }
             WebauthnError::UserAndCredentialDontMatch => "UserID and credentialID don't match",
             WebauthnError::UsernameAlreadyExists => {
                 "Username already exists. Please sign in or choose a different username."
@@ -44,9 +179,52 @@ impl IntoResponse for WebauthnError {
                 "You can only register new credentials for yourself."
             }
             WebauthnError::AlreadySignedIn => "You are already signed in.",
+            WebauthnError::RegistrationReplaced => {
+                "Registration was restarted, e.g. in another tab. Please try again."
+            }
+            WebauthnError::MergeWithSelf => "This credential already belongs to your account.",
+            WebauthnError::AlgorithmNotAllowed => {
+                "The algorithm of this authenticator is not allowed."
+            }
+            WebauthnError::UsernameReserved => {
+                "This username is reserved. Please choose a different username."
+            }
+            WebauthnError::TooManyAuthenticators => {
+                "You have reached the maximum number of credentials."
+            }
+            WebauthnError::DatabaseBusy => "Too many requests, please try again.",
+            WebauthnError::InvalidRequest => "Invalid request body.",
+            WebauthnError::InvalidNickname => "Nickname must be between 1 and 64 characters.",
+            WebauthnError::InvalidTokenName => "Token name must be between 1 and 64 characters.",
+            WebauthnError::TokenNotFound => "Token not found.",
+            WebauthnError::OriginMismatch => "This passkey was created for a different site.",
+            WebauthnError::AccountNoLongerExists => {
+                "The account of this passkey no longer exists. Please register again."
+            }
+            WebauthnError::ReauthRequired => "Please confirm with your passkey first.",
+            WebauthnError::ReauthOtherUser => "This passkey belongs to a different account.",
+            WebauthnError::ProofOfWorkRequired => "Please solve the registration challenge first.",
+            WebauthnError::ProofOfWorkInvalid => {
+                "The solution of the registration challenge is invalid."
+            }
         };
 
         // its often easiest to implement `IntoResponse` by calling other implementations
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        (status, [(ERROR_CODE_HEADER, code)], body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_already_exists_is_conflict() {
+        let response = WebauthnError::UsernameAlreadyExists.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            response.headers().get(ERROR_CODE_HEADER).unwrap(),
+            "USERNAME_ALREADY_EXISTS"
+        );
     }
 }
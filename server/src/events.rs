@@ -0,0 +1,128 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+#[cfg(feature = "events")]
+use crate::{
+    models::User,
+    session::{self, ExtractMe},
+    state::AppState,
+};
+#[cfg(feature = "events")]
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension,
+    },
+    response::Response,
+};
+#[cfg(feature = "events")]
+use futures::{sink::SinkExt, stream::StreamExt};
+#[cfg(feature = "events")]
+use tokio::sync::broadcast::error::RecvError;
+
+// auth and session events of a user, pushed to their clients via /events
+// so the client can react right away instead of on the next request.
+// serialized as json: { "type": "authenticator_added", ... }
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UserEvent {
+    // all sessions of the user are signed out, the socket is closed after it
+    // emitted for the account merged away in finish_merge. Not for a signout,
+    // it only ends the session of one client. Not for --admin delete-user
+    // either, it runs in another process (it deletes the sessions, see
+    // queries::delete_sessions_of_user).
+    SessionRevoked,
+    AuthenticatorAdded { user_agent_short: String },
+}
+
+// number of undelivered events per user, events are rare
+const EVENTS_CAPACITY: usize = 16;
+
+// one broadcast channel per user id with connected clients
+// emitting for a user without connected clients is a no-op. Events of other
+// processes (e.g. --admin delete-user) aren't seen.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    channels: Arc<Mutex<HashMap<Uuid, broadcast::Sender<UserEvent>>>>,
+}
+
+impl EventHub {
+    pub fn subscribe(&self, user_id: Uuid) -> broadcast::Receiver<UserEvent> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(EVENTS_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn emit(&self, user_id: Uuid, event: UserEvent) {
+        if let Some(tx) = self.channels.lock().unwrap().get(&user_id) {
+            // only fails if there are no receivers
+            let _ = tx.send(event);
+        }
+    }
+
+    // drop the channel of a user once their last client disconnected
+    #[cfg_attr(not(feature = "events"), allow(dead_code))]
+    fn unsubscribe(&self, user_id: Uuid) {
+        let mut channels = self.channels.lock().unwrap();
+        if channels
+            .get(&user_id)
+            .is_some_and(|tx| tx.receiver_count() == 0)
+        {
+            channels.remove(&user_id);
+        }
+    }
+}
+
+#[cfg(feature = "events")]
+pub async fn events_handler(
+    ws: WebSocketUpgrade,
+    Extension(app_state): Extension<AppState>,
+    ExtractMe(me): ExtractMe,
+) -> Response {
+    let Some(me) = me else {
+        return session::unauthorized_response();
+    };
+    ws.on_upgrade(move |socket| events_websocket(socket, app_state, me))
+}
+
+// server to client only, messages of the client are ignored
+#[cfg(feature = "events")]
+async fn events_websocket(stream: WebSocket, state: AppState, me: User) {
+    let (mut sender, mut receiver) = stream.split();
+    let mut rx = state.events.subscribe(me.id);
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    let frame = Message::Text(serde_json::to_string(&event).unwrap());
+                    if sender.send(frame).await.is_err() {
+                        break;
+                    }
+                    if matches!(event, UserEvent::SessionRevoked) {
+                        let _ = sender.close().await;
+                        break;
+                    }
+                }
+                // missed events, continue with the oldest one still buffered
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    drop(rx);
+    state.events.unsubscribe(me.id);
+}
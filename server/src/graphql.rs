@@ -1,18 +1,20 @@
 use crate::{
+    error::WebauthnError,
     models::{Authenticator, User},
     queries,
-    session::ExtractMe,
+    session::{self, ExtractMeOrToken},
     state::AppState,
 };
 use async_graphql::{
-    http::GraphiQLSource, ComplexObject, Context, EmptyMutation, EmptySubscription, Json, Object,
-    Schema,
+    http::GraphiQLSource, ComplexObject, Context, EmptyMutation, EmptySubscription,
+    ErrorExtensions, Json, Object, Schema,
 };
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{
     response::{self, IntoResponse},
     Extension,
 };
+use tower_sessions::Session;
 use webauthn_rs::prelude::Passkey;
 
 // graphiql handler
@@ -29,16 +31,30 @@ pub fn build_schema(app_state: AppState) -> GraphQLSchema {
         .finish()
 }
 
-// add req based data to the context
+// remaining seconds of the signed in session, see session_expires_in_seconds
+struct SessionExpiresIn(i64);
+
+// add req based data to the context:
+// - User: me, the session user or the one of the bearer token (ExtractMeOrToken)
+// - SessionExpiresIn: only if the session is signed in, not for tokens
 pub async fn graphql_handler(
     schema: Extension<GraphQLSchema>,
-    ExtractMe(me): ExtractMe,
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeOrToken(me): ExtractMeOrToken,
     req: GraphQLRequest,
 ) -> GraphQLResponse {
     let mut req = req.into_inner();
     if let Some(me) = me {
         req = req.data(me);
     }
+    if session::current_user(&session, &app_state.config)
+        .await
+        .is_some()
+    {
+        let expires_in = session.expiry_date() - cookie::time::OffsetDateTime::now_utc();
+        req = req.data(SessionExpiresIn(expires_in.whole_seconds()));
+    }
     schema.execute(req).await.into()
 }
 
@@ -47,17 +63,22 @@ pub async fn graphql_handler(
 
 #[ComplexObject]
 impl User {
-    async fn authenticators(&self, ctx: &async_graphql::Context<'_>) -> Vec<Authenticator> {
+    async fn authenticators(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<Vec<Authenticator>> {
         let app_state = ctx.data::<AppState>().unwrap();
         let me_id = self.id.clone();
         app_state
             .db
-            .conn
-            .call(move |conn| {
+            .timed_call("get_authenticators_for_user_id", move |conn| {
                 queries::get_authenticators_for_user_id(conn, me_id).map_err(|e| e.into())
             })
             .await
-            .unwrap()
+            .map_err(|e| {
+                error!("get_authenticators_for_user: {:?}", e);
+                WebauthnError::from_db(&e).extend()
+            })
     }
 }
 
@@ -79,4 +100,8 @@ impl Query {
     async fn me(&self, ctx: &Context<'_>) -> Option<User> {
         ctx.data_opt::<User>().cloned()
     }
+    // null if not signed in with a session (anonymous or bearer token)
+    async fn session_expires_in_seconds(&self, ctx: &Context<'_>) -> Option<i64> {
+        ctx.data_opt::<SessionExpiresIn>().map(|s| s.0)
+    }
 }
@@ -0,0 +1,506 @@
+/*
+ * in-process integration tests of the passkey ceremonies
+ *
+ * TestClient drives api_router (with session layer, cookies and AppState, as
+ * served) with oneshot against test_config(): sqlite :memory:, RP_ORIGIN
+ * http://localhost:3000. The session store is migrated on the same connection,
+ * like in main. The client keeps the session cookie like a browser, so
+ * ExtractMe works across requests.
+ *
+ * the authenticator is SoftPasskey of webauthn-authenticator-rs (dev
+ * dependency). It only signs for allowed credentials and doesn't store the
+ * user handle, see TestClient::authenticate for discoverable sign ins.
+ */
+
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_TYPE, COOKIE, SET_COOKIE, USER_AGENT},
+        Method, Request, StatusCode,
+    },
+    Router,
+};
+use cookie::Cookie;
+use serde_json::Value;
+use tower::ServiceExt;
+use tower_sessions_rusqlite_store::RusqliteStore;
+use uuid::Uuid;
+use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+use webauthn_rs::prelude::{
+    AllowCredentials, CreationChallengeResponse, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
+
+use crate::{
+    api_router,
+    config::{test_config, Config},
+    queries, session,
+    state::AppState,
+};
+
+const TEST_USER_AGENT: &str =
+    "Mozilla/5.0 (X11; Linux x86_64; rv:122.0) Gecko/20100101 Firefox/122.0";
+
+pub type TestAuthenticator = WebauthnAuthenticator<SoftPasskey>;
+
+// falsify_uv: reports user verification, passkeys require it
+pub fn soft_passkey() -> TestAuthenticator {
+    WebauthnAuthenticator::new(SoftPasskey::new(true))
+}
+
+// a registered credential, to sign in with it
+#[derive(Clone)]
+pub struct TestCredential {
+    pub cred_id: Vec<u8>,
+    pub user_id: Uuid,
+}
+
+impl TestCredential {
+    // as stored in passkey.cred.cred_id (base64url), see identify_passkey_id
+    pub fn passkey_id(&self) -> String {
+        webauthn_rs::prelude::Base64UrlSafeData::from(self.cred_id.clone()).to_string()
+    }
+}
+
+pub struct TestClient {
+    router: Router,
+    pub app_state: AppState,
+    // the browser's session cookie
+    session_id: Option<String>,
+}
+
+impl TestClient {
+    pub async fn new() -> Self {
+        Self::with_config(test_config()).await
+    }
+
+    pub async fn with_config(config: Config) -> Self {
+        let app_state = AppState::new(config).await;
+        let session_store = RusqliteStore::new(app_state.db.conn.clone());
+        session_store.migrate().await.unwrap();
+        let session_layer = session::session_layer(session_store, &app_state.config);
+        TestClient {
+            router: api_router(app_state.clone(), session_layer),
+            app_state,
+            session_id: None,
+        }
+    }
+
+    // another browser of the same server (same db and store), no cookies
+    pub fn other_browser(&self) -> Self {
+        TestClient {
+            router: self.router.clone(),
+            app_state: self.app_state.clone(),
+            session_id: None,
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    // status and body (json, or the text as json string)
+    pub async fn request(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let config = &self.app_state.config;
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(USER_AGENT, TEST_USER_AGENT);
+        if let Some(session_id) = &self.session_id {
+            request = request.header(COOKIE, format!("{}={session_id}", config.session_name));
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+        let response = self
+            .router
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+
+        let session_cookie = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| Cookie::parse(v.to_str().ok()?.to_string()).ok())
+            .find(|c| c.name() == config.session_name);
+        if let Some(cookie) = session_cookie {
+            // the removal of a deleted session expires right away
+            let removed = cookie.max_age() == Some(time::Duration::ZERO);
+            self.session_id = (!removed).then(|| cookie.value().to_string());
+        }
+
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        (status, body)
+    }
+
+    // register_start and register_finish, the credential if it succeeded
+    pub async fn register(
+        &mut self,
+        authenticator: &mut TestAuthenticator,
+        username: &str,
+    ) -> (StatusCode, Value, Option<TestCredential>) {
+        let (status, body) = self.register_start(username).await;
+        if status != StatusCode::OK {
+            return (status, body, None);
+        }
+        self.register_finish(authenticator, body).await
+    }
+
+    pub async fn register_start(&mut self, username: &str) -> (StatusCode, Value) {
+        let path = format!("/register_start/{username}");
+        self.request(Method::POST, &path, None).await
+    }
+
+    // creates the credential of the challenge (of register_start) and posts it
+    pub async fn register_finish(
+        &mut self,
+        authenticator: &mut TestAuthenticator,
+        challenge: Value,
+    ) -> (StatusCode, Value, Option<TestCredential>) {
+        let ccr: CreationChallengeResponse = serde_json::from_value(challenge).unwrap();
+        let credential: RegisterPublicKeyCredential = authenticator
+            .do_registration(self.app_state.config.rp_origin.clone(), ccr)
+            .unwrap();
+        let raw_id: &[u8] = credential.raw_id.as_ref();
+        let cred_id = raw_id.to_vec();
+
+        let credential = serde_json::to_value(credential).unwrap();
+        let (status, body) = self
+            .request(Method::POST, "/register_finish", Some(credential))
+            .await;
+        let credential = (status == StatusCode::OK).then(|| TestCredential {
+            cred_id,
+            user_id: body["id"].as_str().unwrap().parse().unwrap(),
+        });
+        (status, body, credential)
+    }
+
+    // discoverable sign in (authenticate_start, authenticate_finish) with the
+    // credential. The browser lets the user choose among the resident
+    // credentials, here the soft passkey is told which one to use. A resident
+    // credential returns its user handle (the user id), the soft passkey
+    // doesn't store it: it is set like a device would.
+    pub async fn authenticate(
+        &mut self,
+        authenticator: &mut TestAuthenticator,
+        credential: &TestCredential,
+    ) -> (StatusCode, Value) {
+        let (status, body) = self
+            .request(Method::POST, "/authenticate_start", None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let mut rcr: RequestChallengeResponse = serde_json::from_value(body).unwrap();
+        assert!(rcr.public_key.allow_credentials.is_empty());
+        rcr.public_key.allow_credentials = vec![AllowCredentials {
+            type_: "public-key".to_string(),
+            id: credential.cred_id.clone().into(),
+            transports: None,
+        }];
+        let mut assertion = authenticator
+            .do_authentication(self.app_state.config.rp_origin.clone(), rcr)
+            .unwrap();
+        assertion.response.user_handle = Some(credential.user_id.as_bytes().to_vec().into());
+
+        let assertion = serde_json::to_value(assertion).unwrap();
+        self.request(Method::POST, "/authenticate_finish", Some(assertion))
+            .await
+    }
+
+    // runs f on the db connection
+    pub async fn db<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.app_state
+            .db
+            .conn
+            .call(move |conn| f(conn).map_err(|e| e.into()))
+            .await
+            .unwrap()
+    }
+}
+
+#[tokio::test]
+async fn register_creates_user_and_authenticator() {
+    let mut client = TestClient::new().await;
+    let mut passkey = soft_passkey();
+
+    let (status, body, credential) = client.register(&mut passkey, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["username"], "patte");
+    assert_eq!(body["pending_additional"], true);
+    assert_eq!(body["weak_algorithm"], false);
+    let credential = credential.unwrap();
+
+    let user = client
+        .db(|conn| queries::get_user_by_username(conn, "patte"))
+        .await
+        .expect("user row");
+    assert_eq!(user.id, credential.user_id);
+    let authenticators = client
+        .db(move |conn| queries::get_authenticators_for_user_id(conn, user.id))
+        .await;
+    assert_eq!(authenticators.len(), 1);
+    let stored_cred_id: &[u8] = authenticators[0].passkey.cred_id().as_ref();
+    assert_eq!(stored_cred_id, credential.cred_id.as_slice());
+
+    // signed in as the new user
+    let (status, me) = client.request(Method::GET, "/me", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["username"], "patte");
+}
+
+#[tokio::test]
+async fn register_existing_username_is_rejected() {
+    let mut client = TestClient::new().await;
+    let (status, body, _) = client.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let mut other = client.other_browser();
+    let (status, _, credential) = other.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(credential.is_none());
+}
+
+#[tokio::test]
+async fn authenticate_signs_in() {
+    let mut client = TestClient::new().await;
+    let mut passkey = soft_passkey();
+    let (status, body, credential) = client.register(&mut passkey, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+
+    let mut browser = client.other_browser();
+    let (status, body) = browser.authenticate(&mut passkey, &credential).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["id"], credential.user_id.to_string());
+    assert_eq!(body["username"], "patte");
+    assert!(browser.session_id().is_some());
+
+    let (status, me) = browser.request(Method::GET, "/me", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["id"], credential.user_id.to_string());
+    assert_eq!(me["username"], "patte");
+}
+
+#[tokio::test]
+async fn authenticate_unknown_credential_is_rejected() {
+    let mut client = TestClient::new().await;
+    let (status, body, _) = client.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    // a passkey of another server (another db), unknown to this one
+    let mut passkey = soft_passkey();
+    let mut other_server = TestClient::new().await;
+    let (status, body, credential) = other_server.register(&mut passkey, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+
+    let mut browser = client.other_browser();
+    let (status, _) = browser.authenticate(&mut passkey, &credential).await;
+    assert!(status.is_client_error(), "{status}");
+
+    let (status, _) = browser.request(Method::GET, "/me", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn authenticators_in_registration_order() {
+    let mut client = TestClient::new().await;
+    let mut credentials = vec![];
+    // the second and third are added while signed in
+    for _ in 0..3 {
+        let (status, body, credential) = client.register(&mut soft_passkey(), "patte").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        credentials.push(credential.unwrap().cred_id);
+    }
+
+    let user_id = client
+        .db(|conn| queries::get_user_by_username(conn, "patte"))
+        .await
+        .unwrap()
+        .id;
+    for _ in 0..3 {
+        let authenticators = client
+            .db(move |conn| queries::get_authenticators_for_user_id(conn, user_id))
+            .await;
+        let cred_ids: Vec<Vec<u8>> = authenticators
+            .iter()
+            .map(|a| AsRef::<[u8]>::as_ref(a.passkey.cred_id()).to_vec())
+            .collect();
+        assert_eq!(cred_ids, credentials);
+    }
+}
+
+#[tokio::test]
+async fn register_beyond_max_authenticators_is_rejected() {
+    let config = Config {
+        max_authenticators_per_user: 1,
+        ..test_config()
+    };
+    let mut client = TestClient::with_config(config).await;
+    let (status, body, credential) = client.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let user_id = credential.unwrap().user_id;
+
+    let (status, body, credential) = client.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::CONFLICT, "{body}");
+    assert!(credential.is_none());
+
+    let authenticators = client
+        .db(move |conn| queries::get_authenticators_for_user_id(conn, user_id))
+        .await;
+    assert_eq!(authenticators.len(), 1);
+}
+
+// both registrations start below the limit, only the first to finish is stored
+#[tokio::test]
+async fn concurrent_registrations_beyond_max_authenticators() {
+    let config = Config {
+        max_authenticators_per_user: 2,
+        ..test_config()
+    };
+    let mut client = TestClient::with_config(config).await;
+    let mut passkey = soft_passkey();
+    let (status, body, credential) = client.register(&mut passkey, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+    let mut browser = client.other_browser();
+    let (status, body) = browser.authenticate(&mut passkey, &credential).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let (status, challenge) = client.register_start("patte").await;
+    assert_eq!(status, StatusCode::OK, "{challenge}");
+    let (status, other_challenge) = browser.register_start("patte").await;
+    assert_eq!(status, StatusCode::OK, "{other_challenge}");
+
+    let (status, body, _) = client.register_finish(&mut soft_passkey(), challenge).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, body, _) = browser
+        .register_finish(&mut soft_passkey(), other_challenge)
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{body}");
+
+    let authenticators = client
+        .db(move |conn| queries::get_authenticators_for_user_id(conn, credential.user_id))
+        .await;
+    assert_eq!(authenticators.len(), 2);
+}
+
+#[tokio::test]
+async fn synced_authenticator_reports_backup_state() {
+    let mut client = TestClient::new().await;
+    let (status, body, credential) = client.register(&mut soft_passkey(), "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+
+    // the soft passkey is device bound
+    let (status, authenticators) = client
+        .request(Method::GET, "/me/authenticators", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(authenticators[0]["backup_eligible"], false);
+    assert_eq!(authenticators[0]["backup_state"], false);
+
+    // the flags of an authentication with the credential synced, as stored
+    // by finish_authentication
+    let (user_id, passkey_id) = (credential.user_id, credential.passkey_id());
+    let updated = client
+        .db(move |conn| {
+            queries::update_passkey_for_user_and_passkey_id(
+                conn, user_id, passkey_id, 0, true, true,
+            )
+        })
+        .await;
+    assert_eq!(updated, 1);
+
+    let (status, authenticators) = client
+        .request(Method::GET, "/me/authenticators", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(authenticators[0]["backup_eligible"], true);
+    assert_eq!(authenticators[0]["backup_state"], true);
+}
+
+#[tokio::test]
+async fn authenticator_counter_after_authentication() {
+    let mut client = TestClient::new().await;
+    let mut passkey = soft_passkey();
+    let (status, body, credential) = client.register(&mut passkey, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+
+    let mut browser = client.other_browser();
+    let (status, body) = browser.authenticate(&mut passkey, &credential).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    // the counter of the stored passkey, as updated by finish_authentication
+    let (status, authenticators) = browser
+        .request(Method::GET, "/me/authenticators", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        authenticators[0]["counter"],
+        authenticators[0]["passkey"]["cred"]["counter"]
+    );
+
+    // the counter of a later authentication (of a hardware key)
+    let (user_id, passkey_id) = (credential.user_id, credential.passkey_id());
+    let updated = client
+        .db(move |conn| {
+            queries::update_passkey_for_user_and_passkey_id(
+                conn, user_id, passkey_id, 42, false, false,
+            )
+        })
+        .await;
+    assert_eq!(updated, 1);
+
+    let (status, authenticators) = browser
+        .request(Method::GET, "/me/authenticators", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(authenticators[0]["counter"], 42);
+    assert_eq!(authenticators[0]["passkey"]["cred"]["counter"], 42);
+}
+
+#[tokio::test]
+async fn authenticate_returns_the_used_authenticator() {
+    let mut client = TestClient::new().await;
+    let mut first = soft_passkey();
+    let (status, body, _) = client.register(&mut first, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let mut second = soft_passkey();
+    let (status, body, credential) = client.register(&mut second, "patte").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let credential = credential.unwrap();
+
+    let mut browser = client.other_browser();
+    let (status, body) = browser.authenticate(&mut second, &credential).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["username"], "patte");
+    assert_eq!(
+        body["used_authenticator"]["cred_id"],
+        credential.passkey_id()
+    );
+    assert_eq!(body["used_authenticator"]["nickname"], Value::Null);
+    assert!(body["used_authenticator"]["user_agent_short"]
+        .as_str()
+        .unwrap()
+        .contains("Firefox"));
+}
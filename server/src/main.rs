@@ -1,9 +1,12 @@
 use axum::{
     extract::Extension,
-    http::StatusCode,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, HeaderName, Method, StatusCode, Uri,
+    },
     middleware,
-    response::IntoResponse,
-    routing::{get, post},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Router,
 };
 
@@ -11,18 +14,20 @@ use axum::{
 use axum_embed::ServeEmbed;
 #[cfg(not(feature = "dev_proxy"))]
 use rust_embed::RustEmbed;
+#[cfg(not(feature = "dev_proxy"))]
+use tower::ServiceExt;
 
-use std::{net::SocketAddr, str::FromStr};
 use tower_cookies::CookieManagerLayer;
-use tower_sessions::{
-    cookie::{time::Duration, SameSite},
-    session_store::ExpiredDeletion,
-    Expiry, SessionManagerLayer,
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    trace::TraceLayer,
 };
+use tower_sessions::SessionManagerLayer;
 use tower_sessions_rusqlite_store::RusqliteStore;
 
 mod error;
 
+use crate::config::{Config, LogFormat};
 use crate::state::AppState;
 
 // enables !info, !warn, etc.
@@ -31,12 +36,28 @@ extern crate tracing;
 
 mod session;
 
+mod admin;
+mod api_token;
 mod auth;
+#[cfg(feature = "chat")]
+mod chat;
+mod client_ip;
+mod config;
 mod db;
+mod events;
+#[cfg(feature = "graphql")]
 mod graphql;
+#[cfg(test)]
+mod integration_tests;
 mod models;
+mod openapi;
+mod payload;
 mod queries;
+mod random;
+mod reauth;
 mod state;
+#[cfg(feature = "test_hooks")]
+mod test_hooks;
 mod ua {
     pub mod user_agent;
 }
@@ -45,85 +66,136 @@ mod ua {
 mod proxy;
 
 use dotenv::dotenv;
-use std::env;
+use std::{env, net::SocketAddr};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
 #[cfg(not(feature = "dev_proxy"))]
 #[derive(RustEmbed, Clone)]
 #[folder = "../client/dist/"]
 struct ClientDist;
 
+// serve the embedded client, with history fallback for client side routes:
+// GET requests for paths without file extension which aren't a file of the
+// client nor an api path get index.html, so deep links (e.g. /account) work
+// on refresh. Missing assets (e.g. /foo.js) stay 404s.
+// api routes are matched before /*path, unknown api paths (see is_api_path)
+// get the json 404 of handler_404 instead of the client.
+#[cfg(not(feature = "dev_proxy"))]
+async fn serve_client(mut request: axum::extract::Request) -> Response {
+    let path = request.uri().path();
+    if is_api_path(path) {
+        return handler_404(request.uri().clone(), request.headers().clone()).await;
+    }
+    let is_client_route = !path.rsplit('/').next().unwrap_or("").contains('.')
+        && ClientDist::get(path.trim_start_matches('/')).is_none();
+    if is_client_route {
+        *request.uri_mut() = Uri::from_static("/");
+    }
+    match ServeEmbed::<ClientDist>::new().oneshot(request).await {
+        Ok(response) => response.into_response(),
+        Err(infallible) => match infallible {},
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // load env
     dotenv().ok();
 
     set_default_env_var("RUST_LOG", "INFO");
-    set_default_env_var("LISTEN_HOST_PORT", "0.0.0.0:3000");
-    set_default_env_var("DATABASE_URL", "sqlite://sqlite.db");
 
-    // initialize tracing
-    tracing_subscriber::fmt::init();
+    // parse and validate config, fails fast on misconfiguration
+    let config = Config::from_env();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let is_admin_command = args.first().map(String::as_str) == Some("--admin");
+
+    // initialize tracing, RUST_LOG filters by level and target
+    // admin commands log to stderr, their output (e.g. an export) is stdout
+    let writer = if is_admin_command {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(writer);
+    match config.log_format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).init(),
+    }
+
+    // `server --admin <command>` runs an admin command and exits, see admin.rs
+    if is_admin_command {
+        let db = db::DB::new(&config).await;
+        return admin::run(&db, &config, &args[1..]).await;
+    }
 
     // initialize app state
-    let app_state = AppState::new().await;
+    let app_state = AppState::new(config.clone()).await;
 
     let session_store = RusqliteStore::new(app_state.db.conn.clone());
     session_store.migrate().await.unwrap();
 
-    let deletion_task = tokio::task::spawn(
-        session_store
-            .clone()
-            .continuously_delete_expired(tokio::time::Duration::from_secs(50)),
-    );
+    // `server --check-config`: config, rp, user agent parser and db (including
+    // migrations) are valid if we got here, misconfiguration panics naming the
+    // env var (non-zero exit code). Exits without binding the port.
+    if args.first().map(String::as_str) == Some("--check-config") {
+        println!("config ok");
+        println!(
+            "  rp: {} ({}) {}",
+            config.rp_id, config.rp_name, config.rp_origin
+        );
+        println!("  database: {} (migrated)", config.database_url);
+        println!("  listen: {}", config.listen_addr);
+        return Ok(());
+    }
+
+    let deletion_task = tokio::task::spawn(session::continuously_delete_expired(
+        app_state.clone(),
+        config.session_cleanup_interval,
+        config.session_cleanup_jitter,
+    ));
+    #[cfg(feature = "chat")]
+    let reaper_task = tokio::task::spawn(chat::continuously_reap_presence(
+        app_state.clone(),
+        chat::PRESENCE_REAP_INTERVAL,
+    ));
 
-    // expiry is rolled on requests, see roll_expiry_mw
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_name(&env::var("SESSION_NAME").unwrap_or("session".to_string()))
-        .with_same_site(SameSite::Strict)
-        .with_secure(env::var("COOKIES_SECURE").unwrap_or("true".to_string()) != "false")
-        .with_expiry(Expiry::OnInactivity(Duration::hours(1)));
+    let session_layer = session::session_layer(session_store, &config);
+
+    log_startup_summary(&app_state).await;
 
     // listen
-    let addr = SocketAddr::from_str(&env::var("LISTEN_HOST_PORT").unwrap())
-        .expect("Invalid LISTEN_HOST_PORT environment variable");
+    let addr = config.listen_addr;
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
-    let schema = graphql::build_schema(app_state.clone());
+    let router = api_router(app_state.clone(), session_layer.clone());
 
-    let router = Router::new()
-        .route("/health", get(|| async { "OK" }))
-        .route("/me", get(session::get_me))
-        .route("/me/authenticators", get(session::get_my_authenticators))
-        .route("/debug", get(get_debug))
-        .route(
-            "/graphql",
-            get(graphql::graphiql).post(graphql::graphql_handler),
-        )
-        .route_layer(middleware::from_fn(session::roll_expiry_mw))
-        // ⬇️ these routes don't have the middleware ⬆️ applied
-        .route("/register_start/:username", post(auth::start_register))
-        .route("/register_finish", post(auth::finish_register))
-        .route("/authenticate_start", post(auth::start_authentication))
-        .route("/authenticate_finish", post(auth::finish_authentication))
-        .route("/signout", post(session::signout))
-        .layer(Extension(schema))
-        .layer(Extension(app_state))
-        .layer(session_layer.clone())
-        .layer(CookieManagerLayer::new())
-        .fallback(handler_404);
+    // operator endpoints, on their own listener if ADMIN_LISTEN_HOST_PORT is set
+    let (router, admin_router) = match config.admin_listen_addr {
+        Some(_) => (router, Some(admin_router(app_state.clone()))),
+        None => (router.merge(admin_router(app_state.clone())), None),
+    };
 
     #[cfg(not(feature = "dev_proxy"))]
     {
-        let serve_client = ServeEmbed::<ClientDist>::new();
         let router = Router::new()
-            .nest_service("/", serve_client)
+            .route("/", get(serve_client))
+            .route("/*path", get(serve_client))
             .layer(middleware::from_fn(session::roll_expiry_mw))
             // these layers need to be repeted, roll_expiry_mw needs them
+            .layer(Extension(app_state.clone()))
             .layer(session_layer.clone())
             .layer(CookieManagerLayer::new())
-            .merge(router);
+            .merge(router)
+            .merge(livez_router());
+        let router = with_access_log(router, config.http_access_log);
         info!("Starting server on {addr}");
-        axum::serve(listener, router).await.unwrap();
+        tokio::join!(
+            serve(listener, router, &config),
+            serve_admin(admin_router, &config)
+        );
     }
 
     #[cfg(feature = "dev_proxy")]
@@ -134,23 +206,374 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .route_layer(middleware::from_fn(session::roll_expiry_mw))
             .route("/*key", get(proxy::proxy_handler))
             // these layers need to be repeted, roll_expiry_mw needs them
+            .layer(Extension(app_state.clone()))
             .layer(session_layer.clone())
             .layer(CookieManagerLayer::new())
             .merge(router)
-            .with_state(client);
+            .with_state(client)
+            .merge(livez_router());
+        let router = with_access_log(router, config.http_access_log);
         info!("Starting dev server on {addr}");
-        axum::serve(listener, router).await.unwrap();
+        tokio::join!(
+            serve(listener, router, &config),
+            serve_admin(admin_router, &config)
+        );
     }
 
-    info!("listening on {addr}");
+    // the listener stopped accepting, in-flight requests are done
+    #[cfg(feature = "chat")]
+    chat::notify_shutdown(&app_state).await;
 
-    deletion_task.await??;
+    deletion_task.abort();
+    #[cfg(feature = "chat")]
+    reaper_task.abort();
+    info!("Server stopped");
 
     Ok(())
 }
 
-async fn handler_404() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "404 - Not Found")
+// SIGINT (ctrl-c, fly's default kill signal) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down, no new connections");
+}
+
+// api and auth routes with the layers they need (AppState, session, cookies)
+// without the client. Independent of the listener, so it can also be driven
+// in-process, e.g. with DATABASE_URL=sqlite://:memory:
+fn api_router(app_state: AppState, session_layer: SessionManagerLayer<RusqliteStore>) -> Router {
+    let cors_layer = cors_layer(&app_state.config);
+
+    let router = Router::new()
+        .route("/health", get(session::get_health))
+        .route("/me", get(session::get_me))
+        .route("/me/authenticators", get(session::get_my_authenticators))
+        .route(
+            "/me/tokens",
+            get(api_token::get_my_tokens).post(api_token::create_token),
+        )
+        .route("/me/tokens/:id", delete(api_token::revoke_token))
+        .route("/me/session_info", get(session::get_session_info))
+        .route("/me/refresh_cookie", post(session::refresh_cookie))
+        .route("/me/merge_start", post(auth::start_merge))
+        .route("/me/merge", post(auth::finish_merge))
+        .route("/reauth_start", post(auth::start_reauth))
+        .route("/reauth_finish", post(auth::finish_reauth))
+        .route("/config", get(get_client_config));
+
+    #[cfg(feature = "graphql")]
+    let router = router
+        .route(
+            "/graphql",
+            get(graphql::graphiql).post(graphql::graphql_handler),
+        )
+        .layer(Extension(graphql::build_schema(app_state.clone())));
+
+    #[cfg(feature = "chat")]
+    let router = router.route("/chat", get(chat::websocket_handler));
+
+    #[cfg(feature = "events")]
+    let router = router.route("/events", get(events::events_handler));
+
+    let router = router
+        .route_layer(middleware::from_fn(session::roll_expiry_mw))
+        // ⬇️ these routes don't have the middleware ⬆️ applied
+        .route("/register_start/:username", post(auth::start_register))
+        .route("/register_finish", post(auth::finish_register))
+        .route("/authenticate_start", post(auth::start_authentication))
+        .route("/authenticate_finish", post(auth::finish_authentication))
+        .route(
+            "/authenticate_start_for/:username",
+            post(auth::start_authentication_for),
+        )
+        .route(
+            "/authenticate_finish_for",
+            post(auth::finish_authentication_for),
+        )
+        .route("/signout", post(session::signout))
+        .route("/openapi.json", get(openapi::openapi_json));
+
+    let router = if app_state.config.pow_registration {
+        router.route("/register_challenge", get(auth::register_challenge))
+    } else {
+        router
+    };
+
+    #[cfg(feature = "test_hooks")]
+    let router = router.merge(test_hooks::router());
+
+    let router = router
+        .layer(Extension(app_state))
+        .layer(session_layer)
+        .layer(CookieManagerLayer::new())
+        .fallback(handler_404);
+
+    // outermost, preflights are answered before the session is loaded
+    match cors_layer {
+        Some(cors_layer) => router.layer(cors_layer),
+        None => router,
+    }
+}
+
+// operator endpoints: /debug and /admin/* (gated by ExtractAdmin). Merged into
+// the public router, or served alone on ADMIN_LISTEN_HOST_PORT (see
+// serve_admin). Both share the same AppState (cloned Arcs), this router only
+// has the Extension layer: no session or cookies, operators aren't users.
+fn admin_router(app_state: AppState) -> Router {
+    let router = Router::new()
+        .route("/debug", get(get_debug))
+        .route("/admin/stats", get(admin::get_stats));
+
+    #[cfg(feature = "chat")]
+    let router = router.route("/admin/chat/announce", post(chat::announce_handler));
+
+    router.layer(Extension(app_state))
+}
+
+// plain http on ADMIN_LISTEN_HOST_PORT, e.g. an internal interface not exposed
+// by the proxy. Stops on the same signal as the public listener.
+async fn serve_admin(router: Option<Router>, config: &Config) {
+    let (Some(router), Some(addr)) = (router, config.admin_listen_addr) else {
+        return;
+    };
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    info!("Starting admin server on {addr}");
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+}
+
+// access log (HTTP_ACCESS_LOG=true): method, path, status and latency of every
+// request, outermost so it includes all layers. Only these fields are logged,
+// never headers (cookies, set-cookie: session ids), the query or bodies
+// (credentials), the span and callbacks below don't touch them.
+fn with_access_log(router: Router, enabled: bool) -> Router {
+    if !enabled {
+        return router;
+    }
+    router.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|request: &axum::extract::Request| {
+                info_span!(
+                    "http",
+                    method = %request.method(),
+                    path = %request.uri().path(),
+                )
+            })
+            .on_request(())
+            .on_response(
+                |response: &Response, latency: std::time::Duration, _span: &tracing::Span| {
+                    info!(
+                        status = response.status().as_u16(),
+                        latency_ms = latency.as_millis() as u64,
+                        "response"
+                    );
+                },
+            )
+            .on_body_chunk(())
+            .on_eos(()),
+    )
+}
+
+// cors for a client served from another origin (CORS_ALLOWED_ORIGINS), none
+// if empty (default, client and api on the same origin). The session cookie is
+// SameSite=Strict, so the client must be on the same site, e.g.
+// app.example.com and api.example.com.
+// max_age: preflights are cached by browsers for CORS_MAX_AGE_SECONDS, without
+// it every auth call is preceded by a preflight round trip.
+fn cors_layer(config: &Config) -> Option<CorsLayer> {
+    if config.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    // browsers cap it (chromium: 2 hours, firefox: 24 hours)
+    if config.cors_max_age.as_secs() > 7200 {
+        warn!(
+            "CORS_MAX_AGE_SECONDS={} is higher than browsers cache preflights (chromium: 7200)",
+            config.cors_max_age.as_secs()
+        );
+    }
+    let origins = config
+        .cors_allowed_origins
+        .iter()
+        .map(|origin| {
+            origin
+                .parse()
+                .expect("Invalid CORS_ALLOWED_ORIGINS environment variable")
+        })
+        .collect::<Vec<_>>();
+    Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([
+                CONTENT_TYPE,
+                ACCEPT,
+                HeaderName::from_static("x-pow-nonce"),
+                HeaderName::from_static("x-reauth-token"),
+            ])
+            .expose_headers([
+                HeaderName::from_static("x-error-code"),
+                HeaderName::from_static("x-session-expires"),
+            ])
+            .max_age(config.cors_max_age),
+    )
+}
+
+// serve over tls if TLS_CERT_PATH and TLS_KEY_PATH are set (feature tls),
+// plain http otherwise. Both support http/2: with tls it's negotiated via
+// alpn (h2, http/1.1), without tls clients can use h2c with prior knowledge.
+// The websocket upgrade of /chat needs http/1.1. http/2 extended connect is
+// not advertised, so browsers open a separate http/1.1 connection for it.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn serve(listener: tokio::net::TcpListener, router: Router, config: &Config) {
+    #[cfg(feature = "tls")]
+    if let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) {
+        let tls_config = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert_path, key_path)
+            .await
+            .expect("Invalid TLS_CERT_PATH or TLS_KEY_PATH");
+        info!("TLS enabled, negotiating h2 and http/1.1");
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown_signal().await;
+                handle.graceful_shutdown(Some(std::time::Duration::from_secs(10)));
+            }
+        });
+        axum_server::from_tcp_rustls(listener.into_std().unwrap(), tls_config)
+            .handle(handle)
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+        return;
+    }
+
+    // ConnectInfo: socket peer address for ClientIp
+    // graceful: stops accepting on the signal, waits for in-flight requests
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+}
+
+// effective config of the deployment in one structured line, to confirm it
+// from the first log lines. No secrets: tokens are only logged as set or not.
+async fn log_startup_summary(app_state: &AppState) {
+    let config = &app_state.config;
+    let schema_version = app_state
+        .db
+        .timed_call("schema_version", |conn| {
+            queries::schema_version(conn).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| error!("schema_version: {:?}", e))
+        .ok();
+    let features: Vec<&str> = [
+        ("chat", cfg!(feature = "chat")),
+        ("events", cfg!(feature = "events")),
+        ("graphql", cfg!(feature = "graphql")),
+        ("tls", cfg!(feature = "tls")),
+        ("dev_proxy", cfg!(feature = "dev_proxy")),
+        ("test_hooks", cfg!(feature = "test_hooks")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    info!(
+        rp_id = %config.rp_id,
+        rp_origin = %config.rp_origin,
+        database_url = %config.database_url,
+        schema_version = ?schema_version,
+        features = %features.join(","),
+        session_expiry = %format!("on_inactivity_{}h", session::SESSION_INACTIVITY_HOURS),
+        cookies_secure = config.cookies_secure,
+        cookie_domain = ?config.cookie_domain,
+        info_cookie = config.info_cookie_enabled,
+        admin_token_set = config.admin_token.is_some(),
+        api_token_set = config.api_token.is_some(),
+        "Startup summary"
+    );
+}
+
+// liveness probe: only reflects that the http server is up.
+// merged last, after all .layer(...) calls, so it never runs through the
+// session or cookie layers and a session store outage can't affect it.
+// keep it outside the session middleware! (/health runs through it)
+fn livez_router() -> Router {
+    Router::new().route("/livez", get(|| async { "OK" }))
+}
+
+// paths of api_router, unknown paths under them are api requests
+// prefixes ending with _ match any continuation (/register_start/..), the
+// others whole segments only: /me and /me/foo, but not the client's /members
+const API_PATH_PREFIXES: [&str; 15] = [
+    "/me",
+    "/config",
+    "/admin",
+    "/graphql",
+    "/register_",
+    "/authenticate_",
+    "/reauth_",
+    "/signout",
+    "/chat",
+    "/events",
+    "/debug",
+    "/health",
+    "/livez",
+    "/test_hooks",
+    "/openapi.json",
+];
+
+fn is_api_path(path: &str) -> bool {
+    API_PATH_PREFIXES.iter().any(|p| {
+        if p.ends_with('_') {
+            path.starts_with(p)
+        } else {
+            path == *p || path.starts_with(&format!("{p}/"))
+        }
+    })
+}
+
+// json for api requests and clients asking for json, plain text otherwise
+async fn handler_404(uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path();
+    let wants_json = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json || is_api_path(path) {
+        let body = serde_json::json!({ "error": "not_found", "path": path });
+        (StatusCode::NOT_FOUND, axum::Json(body)).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "404 - Not Found").into_response()
+    }
 }
 
 fn set_default_env_var(key: &str, value: &str) {
@@ -159,24 +582,78 @@ fn set_default_env_var(key: &str, value: &str) {
     }
 }
 
-async fn get_debug(headers: axum::http::HeaderMap) -> impl IntoResponse {
-    let env_primary_region = std::env::var("PRIMARY_REGION").unwrap_or("".to_string());
-    let env_region = std::env::var("FLY_REGION").unwrap_or("".to_string());
-    let machine_is_in_primary_region = env_primary_region == env_region && env_region != "";
-    let req_region = headers
-        .get("Fly-Region")
-        .map(|v| v.to_str().unwrap_or(""))
-        .unwrap_or("");
-    let req_via = headers
-        .get("Via")
-        .map(|v| v.to_str().unwrap_or(""))
-        .unwrap_or("");
+async fn get_debug(
+    Extension(app_state): Extension<AppState>,
+    headers: axum::http::HeaderMap,
+) -> impl IntoResponse {
+    let config = &app_state.config;
+    let env_primary_region = config.primary_region.clone();
+    let env_region = config.fly_region.clone();
+    // null if not running on fly (FLY_REGION unset), e.g. locally
+    let machine_is_in_primary_region =
+        (!env_region.is_empty()).then(|| env_primary_region == env_region);
+    // headers are set by the fly proxy, but can be sent by anyone
+    let req_region = sanitized_header(&headers, "Fly-Region", 16);
+    let req_via = sanitized_header(&headers, "Via", 128);
     axum::Json(serde_json::json!({
-        "FLY_MACHINE_ID": std::env::var("FLY_MACHINE_ID").unwrap_or("".to_string()),
+        "FLY_MACHINE_ID": config.fly_machine_id,
         "PRIMARY_REGION": env_primary_region,
         "FLY_REGION": env_region,
         "machine_is_in_primary_region": machine_is_in_primary_region,
         "req_region": req_region,
         "req_via": req_via,
+        // null without the chat feature
+        "chat_connections": chat_connections(&app_state),
+    }))
+}
+
+// open chat websockets, see CHAT_MAX_CONNECTIONS
+#[cfg(feature = "chat")]
+fn chat_connections(app_state: &AppState) -> Option<usize> {
+    Some(
+        app_state
+            .chat_connections
+            .load(std::sync::atomic::Ordering::Relaxed),
+    )
+}
+
+#[cfg(not(feature = "chat"))]
+fn chat_connections(_app_state: &AppState) -> Option<usize> {
+    None
+}
+
+// public capabilities for feature detection, read once by the client to adapt
+// its ui. Sourced from the config and features the server runs with.
+// registration is always open (no invites) for now.
+async fn get_client_config(Extension(app_state): Extension<AppState>) -> impl IntoResponse {
+    let config = &app_state.config;
+    axum::Json(serde_json::json!({
+        "rp_id": config.rp_id,
+        "rp_name": config.rp_name,
+        "features": {
+            "chat": cfg!(feature = "chat"),
+            "events": cfg!(feature = "events"),
+            "graphql": cfg!(feature = "graphql"),
+            "registration_open": true,
+            "invite_required": false,
+            "proof_of_work": config.pow_registration,
+        },
+        "username": {
+            "min_len": auth::USERNAME_MIN_LEN,
+            "max_len": auth::USERNAME_MAX_LEN,
+            "allow_unicode": config.username_allow_unicode,
+        },
     }))
 }
+
+// header value with printable ascii only, truncated to max_len. None if missing
+fn sanitized_header(headers: &axum::http::HeaderMap, name: &str, max_len: usize) -> Option<String> {
+    headers.get(name).map(|v| {
+        v.as_bytes()
+            .iter()
+            .filter(|b| b.is_ascii_graphic() || **b == b' ')
+            .take(max_len)
+            .map(|b| *b as char)
+            .collect()
+    })
+}
@@ -1,11 +1,13 @@
+#[cfg(feature = "graphql")]
 use async_graphql::SimpleObject;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use uuid::Uuid;
 use webauthn_rs::prelude::Passkey;
 
-#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
-#[graphql(complex)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[cfg_attr(feature = "graphql", derive(SimpleObject), graphql(complex))]
 pub struct User {
     pub id: Uuid,
     pub username: String,
@@ -21,12 +23,44 @@ impl User {
     }
 }
 
-#[derive(Debug, Clone, Serialize, SimpleObject)]
-#[graphql(complex)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[cfg_attr(feature = "graphql", derive(SimpleObject), graphql(complex))]
 pub struct Authenticator {
     pub user_id: Uuid,
-    #[graphql(skip)]
+    #[cfg_attr(feature = "graphql", graphql(skip))]
+    #[schema(value_type = Object)]
     pub passkey: Passkey,
+    // "browser - os - device" of the registration, derived from the ua_ fields
     pub user_agent_short: String,
+    // chosen at registration, clients show user_agent_short if None
+    pub nickname: Option<String>,
+    // parsed user agent of the registration, None if unknown (registered
+    // before they were stored or without `User-Agent` header)
+    pub ua_browser: Option<String>,
+    pub ua_os: Option<String>,
+    pub ua_device: Option<String>,
+    // from the stored passkey (updated on every authentication), backup_state:
+    // the credential is synced (e.g. icloud keychain, google password manager)
+    pub backup_eligible: bool,
+    pub backup_state: bool,
+    // signature counter of the last authentication, stays 0 for most synced
+    // (software) passkeys, increments for hardware keys
+    pub counter: u32,
     pub created_at: DateTime<Utc>,
+    // credProps.rk reported at registration, None if unknown
+    // if false, discoverable authentication can't find this credential
+    pub discoverable: Option<bool>,
+    // transport hints (usb, nfc, ble, internal, hybrid) reported at
+    // registration, empty if unknown
+    pub transports: Vec<String>,
+}
+
+// per user api token, without the secret (shown once at creation)
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
 }
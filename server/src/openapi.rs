@@ -0,0 +1,51 @@
+use axum::{response::IntoResponse, Json};
+use utoipa::OpenApi;
+
+use crate::{
+    api_token, auth,
+    models::{ApiToken, Authenticator, User},
+    session,
+};
+
+/*
+ * OpenAPI description of the rest endpoints, served at /openapi.json
+ * paths are annotated with #[utoipa::path] at the handlers. Webauthn-rs types
+ * (challenges and credentials) are described as objects, see the webauthn spec.
+ * Errors are plain text (see error.rs), with the status code of the variant.
+ */
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        session::get_me,
+        session::get_my_authenticators,
+        session::get_session_info,
+        session::refresh_cookie,
+        session::signout,
+        api_token::create_token,
+        api_token::get_my_tokens,
+        api_token::revoke_token,
+        auth::start_register,
+        auth::finish_register,
+        auth::start_authentication,
+        auth::finish_authentication,
+    ),
+    components(schemas(
+        User,
+        Authenticator,
+        ApiToken,
+        api_token::CreateTokenParams,
+        api_token::CreateTokenResponse,
+        auth::RegisterFinishResponse,
+        auth::AuthenticateFinishResponse,
+        auth::UsedAuthenticator,
+        session::MeResponse,
+        session::SessionInfo,
+        session::SignoutResponse
+    ))
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_json() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
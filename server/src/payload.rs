@@ -0,0 +1,135 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Json, Request},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::WebauthnError;
+
+// request body extractor for the auth finish endpoints
+// branches on the `Content-Type` header before any body is read:
+//   application/cbor    -> CBOR (binary fields as byte strings or base64url)
+//   application/msgpack -> MessagePack
+//   anything else       -> json via axum's Json extractor (default)
+// attestation objects are a lot smaller without base64 in json, which matters
+// for constrained clients.
+// bodies which don't deserialize are rejected with WebauthnError::InvalidRequest
+// (400, like all other api errors), the details are only logged.
+pub struct Payload<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+
+        if content_type.starts_with("application/cbor") {
+            let bytes = Bytes::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            let value = ciborium::de::from_reader(bytes.as_ref()).map_err(|e| {
+                info!("Invalid CBOR body: {:?}", e);
+                WebauthnError::InvalidRequest.into_response()
+            })?;
+            Ok(Payload(value))
+        } else if content_type.starts_with("application/msgpack") {
+            let bytes = Bytes::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            let value = rmp_serde::from_slice(&bytes).map_err(|e| {
+                info!("Invalid MessagePack body: {:?}", e);
+                WebauthnError::InvalidRequest.into_response()
+            })?;
+            Ok(Payload(value))
+        } else {
+            let Json(value) = Json::<T>::from_request(req, state).await.map_err(|e| {
+                info!("Invalid json body: {}", e.body_text());
+                WebauthnError::InvalidRequest.into_response()
+            })?;
+            Ok(Payload(value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode};
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Credential {
+        id: String,
+        counter: u32,
+    }
+
+    fn credential() -> Credential {
+        Credential {
+            id: "cred".to_string(),
+            counter: 7,
+        }
+    }
+
+    async fn extract(content_type: &str, body: Vec<u8>) -> Result<Credential, Response> {
+        let req = axum::http::Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        Payload::<Credential>::from_request(req, &())
+            .await
+            .map(|Payload(value)| value)
+    }
+
+    #[tokio::test]
+    async fn json_is_the_default() {
+        let body = serde_json::to_vec(&credential()).unwrap();
+        assert_eq!(
+            extract("application/json", body).await.unwrap(),
+            credential()
+        );
+    }
+
+    #[tokio::test]
+    async fn cbor() {
+        let mut body = Vec::new();
+        ciborium::ser::into_writer(&credential(), &mut body).unwrap();
+        assert_eq!(
+            extract("application/cbor", body).await.unwrap(),
+            credential()
+        );
+    }
+
+    #[tokio::test]
+    async fn msgpack() {
+        let body = rmp_serde::to_vec_named(&credential()).unwrap();
+        assert_eq!(
+            extract("application/msgpack", body).await.unwrap(),
+            credential()
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_bodies_are_bad_requests() {
+        let json = serde_json::to_vec(&credential()).unwrap();
+        for (content_type, body) in [
+            ("application/cbor", json.clone()),
+            ("application/msgpack", json),
+            ("application/json", b"{".to_vec()),
+        ] {
+            let response = extract(content_type, body).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{content_type}");
+        }
+    }
+}
@@ -1,14 +1,25 @@
 use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection, Result};
+use serde::{Deserialize, Serialize};
+use tower_sessions::session::Record;
 use uuid::Uuid;
-use webauthn_rs::prelude::Passkey;
+use webauthn_rs::prelude::{AuthenticatorTransport, Passkey};
 
-use crate::models::{Authenticator, User};
+use crate::models::{ApiToken, Authenticator, User};
+use crate::ua::user_agent::{get_user_agent_string_short, ParsedUserAgent};
 
 // db queries
 // Intentionally using rusqlite and not tokio_rusqlite
 // the async wrapping is done where the queries are called.
 
+// table of tower-sessions-rusqlite-store (default name)
+const SESSIONS_TABLE: &str = "tower_sessions";
+
+// number of applied migrations (rusqlite_migration keeps it in user_version)
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    conn.query_row("pragma user_version", [], |row| row.get(0))
+}
+
 pub fn insert_user(conn: &Connection, user: User) -> Result<usize> {
     conn.execute(
         "insert into
@@ -18,22 +29,42 @@ pub fn insert_user(conn: &Connection, user: User) -> Result<usize> {
     )
 }
 
+// a credential of finish_register, with what the client reported about it
+#[derive(Debug, Clone)]
+pub struct NewAuthenticator {
+    pub passkey: Passkey,
+    pub user_agent: Option<ParsedUserAgent>,
+    pub discoverable: Option<bool>,
+    pub transports: Option<Vec<AuthenticatorTransport>>,
+    pub nickname: Option<String>,
+}
+
 pub fn insert_authenticator(
     conn: &Connection,
     user_id: Uuid,
-    passkey: Passkey,
     created_at: DateTime<Utc>,
-    user_agent_short: &str,
+    authenticator: &NewAuthenticator,
 ) -> Result<usize> {
+    let user_agent = authenticator.user_agent.as_ref();
     conn.execute(
         "insert into
-        authenticators (user_id, passkey, created_at, user_agent_short)
-        values (?1, ?2, ?3, ?4)",
+        authenticators (user_id, passkey, created_at, user_agent_short, discoverable, transports,
+            ua_browser, ua_os, ua_device, nickname)
+        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
         params![
             user_id,
-            serde_json::to_string(&passkey).unwrap(),
+            serde_json::to_string(&authenticator.passkey).unwrap(),
             created_at.to_rfc3339(),
-            user_agent_short
+            get_user_agent_string_short(user_agent),
+            authenticator.discoverable,
+            authenticator
+                .transports
+                .as_ref()
+                .map(|t| serde_json::to_string(t).unwrap()),
+            user_agent.map(|ua| &ua.browser),
+            user_agent.map(|ua| &ua.os),
+            user_agent.map(|ua| &ua.device),
+            authenticator.nickname
         ],
     )
 }
@@ -41,25 +72,74 @@ pub fn insert_authenticator(
 pub fn insert_user_and_passkey(
     conn: &mut Connection,
     user: User,
-    passkey: Passkey,
-    user_agent_short: &str,
+    authenticator: &NewAuthenticator,
 ) -> Result<()> {
     let tx = conn.transaction()?;
 
     insert_user(&tx, user.clone())?;
 
-    insert_authenticator(&tx, user.id, passkey, user.created_at, user_agent_short)?;
+    insert_authenticator(&tx, user.id, user.created_at, authenticator)?;
 
     tx.commit()?;
     Ok(())
 }
 
+// insert_authenticator unless user_id has max_authenticators already, counted
+// in the transaction: concurrent registrations all passed the check of
+// start_register. false if nothing was inserted.
+pub fn insert_authenticator_within_limit(
+    conn: &mut Connection,
+    user_id: Uuid,
+    created_at: DateTime<Utc>,
+    authenticator: &NewAuthenticator,
+    max_authenticators: usize,
+) -> Result<bool> {
+    let tx = conn.transaction()?;
+
+    let count: usize = tx.query_row(
+        "select count(*) from authenticators where user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?;
+    if count >= max_authenticators {
+        return Ok(false);
+    }
+    insert_authenticator(&tx, user_id, created_at, authenticator)?;
+
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn get_user_by_username(conn: &Connection, username: &str) -> Result<Option<User>> {
+    let mut stmt = conn.prepare(
+        "
+        select id, username, created_at
+        from users
+        where username = ?1 and deleted_at is null",
+    )?;
+    let mut rows = stmt.query(params![username])?;
+    let user = rows
+        .next()?
+        .map(|row| -> Result<User> {
+            let created_at_string: String = row.get(2)?;
+            Ok(User {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                created_at: DateTime::parse_from_rfc3339(&created_at_string)
+                    .unwrap()
+                    .to_utc(),
+            })
+        })
+        .transpose()?;
+    Ok(user)
+}
+
 pub fn check_username_exists(conn: &mut Connection, username: &str) -> Result<bool> {
     let mut stmt = conn.prepare(
         "
         select id
         from users
-        where username = ?1
+        where username = ?1 and deleted_at is null
         ",
     )?;
     let mut rows = stmt.query(params![username])?;
@@ -67,27 +147,51 @@ pub fn check_username_exists(conn: &mut Connection, username: &str) -> Result<bo
     Ok(exists)
 }
 
-pub fn get_passkey_for_user_and_passkey_id(
+// the passkey and its owner, by the credential id alone (unique). The user
+// handle on the device can be of another user: merged passkeys keep the
+// handle of the merged-away account, see merge_users.
+pub fn get_passkey_by_passkey_id(
     conn: &Connection,
-    user_id: Uuid,
     passkey_id: String,
-) -> Result<Option<Passkey>> {
+) -> Result<Option<(Uuid, Passkey)>> {
     let mut stmt = conn.prepare(
         "
-        select passkey
+        select user_id, passkey
         from authenticators
+        join users on users.id = authenticators.user_id
         where
-            user_id = ?1 and
-            json_extract(passkey, '$.cred.cred_id') = ?2",
+            json_extract(passkey, '$.cred.cred_id') = ?1 and
+            users.deleted_at is null",
     )?;
-    let mut rows = stmt.query(params![user_id, passkey_id])?;
-    let passkey = rows.next()?.map(|row| {
-        let passkey: String = row.get(0).expect("Failed to get row");
-        serde_json::from_str(&passkey).unwrap()
-    });
+    let mut rows = stmt.query(params![passkey_id])?;
+    let passkey = rows
+        .next()?
+        .map(|row| -> Result<(Uuid, Passkey)> {
+            let passkey: String = row.get(1)?;
+            Ok((row.get(0)?, serde_json::from_str(&passkey).unwrap()))
+        })
+        .transpose()?;
     Ok(passkey)
 }
 
+// nickname and user_agent_short of a credential, e.g. the one used to sign in
+pub fn get_authenticator_names(
+    conn: &Connection,
+    user_id: Uuid,
+    passkey_id: String,
+) -> Result<(Option<String>, String)> {
+    conn.query_row(
+        "
+        select nickname, user_agent_short
+        from authenticators
+        where
+            user_id = ?1 and
+            json_extract(passkey, '$.cred.cred_id') = ?2",
+        params![user_id, passkey_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
 pub fn update_passkey_for_user_and_passkey_id(
     conn: &Connection,
     user_id: Uuid,
@@ -126,7 +230,7 @@ pub fn get_user_by_id(conn: &Connection, id: Uuid) -> Result<User> {
         "
         select id, username, created_at
         from users
-        where id = ?1",
+        where id = ?1 and deleted_at is null",
     )?;
     let user = stmt.query_row(params![id], |row| {
         let created_at_string: String = row.get(2)?;
@@ -141,9 +245,9 @@ pub fn get_user_by_id(conn: &Connection, id: Uuid) -> Result<User> {
     Ok(user)
 }
 
-#[allow(dead_code)]
 pub fn get_all_users(conn: &Connection) -> Result<Vec<User>> {
-    let mut stmt = conn.prepare("SELECT id, username, created_at FROM users")?;
+    let mut stmt =
+        conn.prepare("SELECT id, username, created_at FROM users WHERE deleted_at IS NULL")?;
     let users = stmt
         .query_map([], |row| {
             let created_at_string: String = row.get(2)?;
@@ -159,20 +263,142 @@ pub fn get_all_users(conn: &Connection) -> Result<Vec<User>> {
     users
 }
 
+// soft: sets deleted_at, the user and their authenticators stay for the audit
+// trail. Queries treat the user as absent (deleted_at is null filters), their
+// authenticators can't sign in and the username is free again.
+// hard (HARD_DELETE=true): deletes the user and their authenticators.
+// returns the number of deleted users
+pub fn delete_user(conn: &mut Connection, id: Uuid, hard: bool) -> Result<usize> {
+    if !hard {
+        return soft_delete_user(conn, id);
+    }
+    let tx = conn.transaction()?;
+
+    tx.execute("delete from authenticators where user_id = ?1", params![id])?;
+    tx.execute("delete from tokens where user_id = ?1", params![id])?;
+    let deleted = tx.execute("delete from users where id = ?1", params![id])?;
+
+    tx.commit()?;
+    Ok(deleted)
+}
+
+fn soft_delete_user(conn: &Connection, id: Uuid) -> Result<usize> {
+    conn.execute(
+        "update users set deleted_at = ?2 where id = ?1 and deleted_at is null",
+        params![id, Utc::now().to_rfc3339()],
+    )
+}
+
+// the sessions signed in as the user, for deletions and merges. The store
+// keeps the whole session record as MessagePack (see RusqliteStore::save), the
+// user isn't a column: all sessions are read and decoded, fine for these rare
+// calls but not for hot paths. user_key: the session key of the signed in
+// user, see Config::session_key. Returns the number of deleted sessions.
+pub fn delete_sessions_of_user(conn: &Connection, user_id: Uuid, user_key: &str) -> Result<usize> {
+    // the table is created by RusqliteStore::migrate at server start, a fresh
+    // database of the admin commands may not have it yet
+    let has_sessions: bool = conn.query_row(
+        "select exists(select 1 from sqlite_master where type = 'table' and name = ?1)",
+        params![SESSIONS_TABLE],
+        |row| row.get(0),
+    )?;
+    if !has_sessions {
+        return Ok(0);
+    }
+
+    let user_id = user_id.to_string();
+    let mut session_ids = Vec::new();
+    let mut stmt = conn.prepare(&format!("select id, data from {SESSIONS_TABLE}"))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let data: Vec<u8> = row.get(1)?;
+        // not a record of the store, not signed in as anyone we know of
+        let Ok(record) = rmp_serde::from_slice::<Record>(&data) else {
+            continue;
+        };
+        let signed_in_as = record
+            .data
+            .get(user_key)
+            .and_then(|user| user.get("id"))
+            .and_then(|id| id.as_str());
+        if signed_in_as == Some(user_id.as_str()) {
+            session_ids.push(row.get::<_, String>(0)?);
+        }
+    }
+
+    let mut deleted = 0;
+    for session_id in session_ids {
+        deleted += conn.execute(
+            &format!("delete from {SESSIONS_TABLE} where id = ?1"),
+            params![session_id],
+        )?;
+    }
+    Ok(deleted)
+}
+
+// moves the authenticators of from_user_id to into_user_id and deletes
+// from_user_id (see delete_user), returns the number of moved authenticators
+// None (nothing changed) if into_user_id would have more than
+// max_authenticators, counted in the transaction.
+pub fn merge_users(
+    conn: &mut Connection,
+    into_user_id: Uuid,
+    from_user_id: Uuid,
+    hard: bool,
+    max_authenticators: usize,
+) -> Result<Option<usize>> {
+    let tx = conn.transaction()?;
+
+    let total: usize = tx.query_row(
+        "select count(*) from authenticators where user_id in (?1, ?2)",
+        params![into_user_id, from_user_id],
+        |row| row.get(0),
+    )?;
+    if total > max_authenticators {
+        return Ok(None);
+    }
+
+    let moved = tx.execute(
+        "update authenticators set user_id = ?1 where user_id = ?2",
+        params![into_user_id, from_user_id],
+    )?;
+    // tokens act as their user, they aren't moved
+    if hard {
+        tx.execute(
+            "delete from tokens where user_id = ?1",
+            params![from_user_id],
+        )?;
+        tx.execute("delete from users where id = ?1", params![from_user_id])?;
+    } else {
+        soft_delete_user(&tx, from_user_id)?;
+    }
+
+    tx.commit()?;
+    Ok(Some(moved))
+}
+
+// oldest first, rowid orders the ones created in the same instant
 pub fn get_authenticators_for_user_id(
     conn: &Connection,
     user_id: Uuid,
 ) -> Result<Vec<Authenticator>> {
     let mut stmt = conn.prepare(
         "
-        select user_id, passkey, user_agent_short, created_at
+        select user_id, passkey, user_agent_short, created_at, discoverable, transports,
+            ua_browser, ua_os, ua_device,
+            json_extract(passkey, '$.cred.backup_eligible'),
+            json_extract(passkey, '$.cred.backup_state'),
+            json_extract(passkey, '$.cred.counter'),
+            nickname
         from authenticators
-        where user_id = ?1",
+        where user_id = ?1
+        order by created_at asc, user_agent_short, rowid",
     )?;
     let authenticators = stmt
         .query_map(params![user_id], |row| {
             let passkey_string: String = row.get(1)?;
             let created_at_string: String = row.get(3)?;
+            let transports_string: Option<String> = row.get(5)?;
             Ok(Authenticator {
                 user_id: row.get(0)?,
                 passkey: serde_json::from_str(&passkey_string).unwrap(),
@@ -180,8 +406,358 @@ pub fn get_authenticators_for_user_id(
                 created_at: DateTime::parse_from_rfc3339(&created_at_string)
                     .unwrap()
                     .to_utc(),
+                discoverable: row.get(4)?,
+                transports: transports_string
+                    .map(|t| serde_json::from_str(&t).unwrap())
+                    .unwrap_or_default(),
+                ua_browser: row.get(6)?,
+                ua_os: row.get(7)?,
+                ua_device: row.get(8)?,
+                backup_eligible: row.get::<_, Option<bool>>(9)?.unwrap_or(false),
+                backup_state: row.get::<_, Option<bool>>(10)?.unwrap_or(false),
+                counter: row.get::<_, Option<u32>>(11)?.unwrap_or(0),
+                nickname: row.get(12)?,
             })
         })?
         .collect();
     authenticators
 }
+
+pub fn count_authenticators_for_user_id(conn: &Connection, user_id: Uuid) -> Result<i64> {
+    conn.query_row(
+        "select count(*) from authenticators where user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+// aggregate counts of the admin stats, see admin::get_stats
+
+// not deleted
+pub fn count_users(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "select count(*) from users where deleted_at is null",
+        [],
+        |row| row.get(0),
+    )
+}
+
+pub fn count_authenticators(conn: &Connection) -> Result<i64> {
+    conn.query_row("select count(*) from authenticators", [], |row| row.get(0))
+}
+
+// not expired, signed in or not (e.g. a started ceremony)
+pub fn count_active_sessions(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        &format!("select count(*) from {SESSIONS_TABLE} where expiry_date >= ?1"),
+        params![Utc::now().timestamp()],
+        |row| row.get(0),
+    )
+}
+
+// users created since, including deleted ones. created_at is rfc3339 with or
+// without fractional seconds, datetime() compares them as times.
+pub fn count_registrations_since(conn: &Connection, since: DateTime<Utc>) -> Result<i64> {
+    conn.query_row(
+        "select count(*) from users where datetime(created_at) >= datetime(?1)",
+        params![since.to_rfc3339()],
+        |row| row.get(0),
+    )
+}
+
+// same as RusqliteStore::delete_expired, but returns the number of deleted sessions
+// at most limit per call, so a big backlog doesn't block the connection
+// expiry_date is stored as unix timestamp by the store
+pub fn delete_expired_sessions(conn: &Connection, limit: usize) -> Result<usize> {
+    conn.execute(
+        &format!(
+            "delete from {SESSIONS_TABLE} where id in (
+                select id from {SESSIONS_TABLE} where expiry_date < ?1 limit ?2
+            )"
+        ),
+        params![Utc::now().timestamp(), limit],
+    )
+}
+
+pub fn insert_api_token(
+    conn: &Connection,
+    user_id: Uuid,
+    token: &ApiToken,
+    hash: String,
+) -> Result<usize> {
+    conn.execute(
+        "insert into
+        tokens (id, user_id, hash, name, created_at)
+        values (?1, ?2, ?3, ?4, ?5)",
+        params![
+            token.id,
+            user_id,
+            hash,
+            token.name,
+            token.created_at.to_rfc3339()
+        ],
+    )
+}
+
+pub fn get_api_tokens_for_user_id(conn: &Connection, user_id: Uuid) -> Result<Vec<ApiToken>> {
+    let mut stmt = conn.prepare(
+        "
+        select id, name, created_at, last_used_at, revoked
+        from tokens
+        where user_id = ?1
+        order by created_at",
+    )?;
+    let tokens = stmt
+        .query_map(params![user_id], |row| {
+            let created_at_string: String = row.get(2)?;
+            let last_used_at_string: Option<String> = row.get(3)?;
+            Ok(ApiToken {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                created_at: DateTime::parse_from_rfc3339(&created_at_string)
+                    .unwrap()
+                    .to_utc(),
+                last_used_at: last_used_at_string
+                    .map(|s| DateTime::parse_from_rfc3339(&s).unwrap().to_utc()),
+                revoked: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(tokens)
+}
+
+// user and hash of a usable token: not revoked, user not deleted
+pub fn get_api_token_user_and_hash(conn: &Connection, id: Uuid) -> Result<(User, String)> {
+    conn.query_row(
+        "
+        select users.id, users.username, users.created_at, tokens.hash
+        from tokens
+        join users on users.id = tokens.user_id
+        where tokens.id = ?1 and tokens.revoked = 0 and users.deleted_at is null",
+        params![id],
+        |row| {
+            let created_at_string: String = row.get(2)?;
+            let user = User {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                created_at: DateTime::parse_from_rfc3339(&created_at_string)
+                    .unwrap()
+                    .to_utc(),
+            };
+            Ok((user, row.get(3)?))
+        },
+    )
+}
+
+pub fn touch_api_token(conn: &Connection, id: Uuid) -> Result<usize> {
+    conn.execute(
+        "update tokens set last_used_at = ?2 where id = ?1",
+        params![id, Utc::now().to_rfc3339()],
+    )
+}
+
+// returns 0 if the token doesn't exist or belongs to another user
+pub fn revoke_api_token(conn: &Connection, user_id: Uuid, id: Uuid) -> Result<usize> {
+    conn.execute(
+        "update tokens set revoked = 1 where id = ?1 and user_id = ?2",
+        params![id, user_id],
+    )
+}
+
+// authenticators of a user as stored, for moving them to another instance with
+// the same RP_ID (admin export-authenticators / import-authenticators)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthenticatorsExport {
+    pub rp_id: String,
+    pub user: User,
+    pub authenticators: Vec<AuthenticatorExport>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthenticatorExport {
+    pub passkey: Passkey,
+    pub created_at: DateTime<Utc>,
+    pub user_agent_short: Option<String>,
+    pub discoverable: Option<bool>,
+    pub transports: Option<Vec<AuthenticatorTransport>>,
+    pub ua_browser: Option<String>,
+    pub ua_os: Option<String>,
+    pub ua_device: Option<String>,
+    pub nickname: Option<String>,
+}
+
+pub fn export_authenticators(conn: &Connection, user_id: Uuid) -> Result<Vec<AuthenticatorExport>> {
+    let mut stmt = conn.prepare(
+        "
+        select passkey, created_at, user_agent_short, discoverable, transports,
+            ua_browser, ua_os, ua_device, nickname
+        from authenticators
+        where user_id = ?1
+        order by created_at",
+    )?;
+    let authenticators = stmt
+        .query_map(params![user_id], |row| {
+            let passkey_string: String = row.get(0)?;
+            let created_at_string: String = row.get(1)?;
+            let transports_string: Option<String> = row.get(4)?;
+            Ok(AuthenticatorExport {
+                passkey: serde_json::from_str(&passkey_string).unwrap(),
+                created_at: DateTime::parse_from_rfc3339(&created_at_string)
+                    .unwrap()
+                    .to_utc(),
+                user_agent_short: row.get(2)?,
+                discoverable: row.get(3)?,
+                transports: transports_string.and_then(|t| serde_json::from_str(&t).ok()),
+                ua_browser: row.get(5)?,
+                ua_os: row.get(6)?,
+                ua_device: row.get(7)?,
+                nickname: row.get(8)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(authenticators)
+}
+
+// creates the user (same id) if it doesn't exist, all or nothing. Fails if
+// the username is taken by another user or a credential exists already.
+pub fn import_authenticators(
+    conn: &mut Connection,
+    user: User,
+    authenticators: &[AuthenticatorExport],
+) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    let user_exists: bool = tx.query_row(
+        "select exists(select 1 from users where id = ?1 and deleted_at is null)",
+        params![user.id],
+        |row| row.get(0),
+    )?;
+    if !user_exists {
+        insert_user(&tx, user.clone())?;
+    }
+
+    for authenticator in authenticators {
+        tx.execute(
+            "insert into
+            authenticators (user_id, passkey, created_at, user_agent_short, discoverable,
+                transports, ua_browser, ua_os, ua_device, nickname)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                user.id,
+                serde_json::to_string(&authenticator.passkey).unwrap(),
+                authenticator.created_at.to_rfc3339(),
+                authenticator.user_agent_short,
+                authenticator.discoverable,
+                authenticator
+                    .transports
+                    .as_ref()
+                    .map(|t| serde_json::to_string(t).unwrap()),
+                authenticator.ua_browser,
+                authenticator.ua_os,
+                authenticator.ua_device,
+                authenticator.nickname
+            ],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(authenticators.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // schema of tower-sessions-rusqlite-store, created by RusqliteStore::migrate
+    fn sessions_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "create table {SESSIONS_TABLE} (
+                id text primary key not null,
+                data blob not null,
+                expiry_date integer not null
+            )"
+        ))
+        .unwrap();
+        conn
+    }
+
+    fn insert_session(conn: &Connection, id: &str, expiry_date: i64) {
+        conn.execute(
+            &format!("insert into {SESSIONS_TABLE} (id, data, expiry_date) values (?1, x'', ?2)"),
+            params![id, expiry_date],
+        )
+        .unwrap();
+    }
+
+    fn insert_signed_in_session(conn: &Connection, user: Option<&User>) {
+        let mut data = std::collections::HashMap::new();
+        if let Some(user) = user {
+            data.insert(
+                "authenticated_user".to_string(),
+                serde_json::to_value(user).unwrap(),
+            );
+        }
+        let record = Record {
+            id: Default::default(),
+            data,
+            expiry_date: time::OffsetDateTime::now_utc() + time::Duration::hours(1),
+        };
+        conn.execute(
+            &format!("insert into {SESSIONS_TABLE} (id, data, expiry_date) values (?1, ?2, ?3)"),
+            params![
+                record.id.to_string(),
+                rmp_serde::to_vec(&record).unwrap(),
+                record.expiry_date.unix_timestamp()
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn delete_sessions_of_user_keeps_other_sessions() {
+        let conn = sessions_conn();
+        let user = User::new("patte".to_string());
+        let other_user = User::new("other".to_string());
+        insert_signed_in_session(&conn, Some(&user));
+        insert_signed_in_session(&conn, Some(&user));
+        insert_signed_in_session(&conn, Some(&other_user));
+        insert_signed_in_session(&conn, None);
+
+        assert_eq!(
+            delete_sessions_of_user(&conn, user.id, "authenticated_user").unwrap(),
+            2
+        );
+        assert_eq!(count_active_sessions(&conn).unwrap(), 2);
+        assert_eq!(
+            delete_sessions_of_user(&conn, user.id, "authenticated_user").unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn delete_sessions_of_user_without_sessions_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(
+            delete_sessions_of_user(&conn, Uuid::now_v7(), "authenticated_user").unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn delete_expired_sessions_counts_deleted_rows() {
+        let conn = sessions_conn();
+        let now = Utc::now().timestamp();
+        for i in 0..3 {
+            insert_session(&conn, &format!("expired-{i}"), now - 60 - i);
+        }
+        insert_session(&conn, "valid-0", now + 60);
+        insert_session(&conn, "valid-1", now + 3600);
+
+        // chunked by the limit
+        assert_eq!(delete_expired_sessions(&conn, 2).unwrap(), 2);
+        assert_eq!(delete_expired_sessions(&conn, 2).unwrap(), 1);
+        assert_eq!(delete_expired_sessions(&conn, 2).unwrap(), 0);
+        assert_eq!(count_active_sessions(&conn).unwrap(), 2);
+    }
+}
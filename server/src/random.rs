@@ -0,0 +1,14 @@
+/*
+ * os randomness for secrets (api tokens, reauth key), challenges and jitter.
+ * argon2 salts are generated by argon2 itself (SaltString::generate).
+ */
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("Failed to read from the os random source");
+    bytes
+}
+
+pub fn random_u64() -> u64 {
+    u64::from_le_bytes(random_bytes())
+}
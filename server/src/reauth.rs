@@ -0,0 +1,98 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    admin::constant_time_eq, error::WebauthnError, models::User, session::ExtractMeEnsure,
+    state::AppState,
+};
+
+/*
+ * step up authentication: a fresh passkey assertion of the signed in user
+ * (/reauth_start, /reauth_finish in auth.rs) yields a reauth token, which
+ * sensitive operations require in the `X-Reauth-Token` header, see ExtractReauth.
+ * token: <user id>.<expires unix>.<hmac-sha256 hex>, stateless, the session
+ * isn't touched. Valid for REAUTH_TOKEN_SECONDS, also more than once.
+ */
+
+pub const REAUTH_TOKEN_HEADER: &str = "x-reauth-token";
+pub const REAUTH_TOKEN_SECONDS: i64 = 120;
+
+// random per process (AppState::reauth_key), tokens don't survive a restart
+pub const REAUTH_KEY_LEN: usize = 32;
+
+pub fn sign_token(key: &[u8], user_id: Uuid) -> (String, i64) {
+    let expires = Utc::now().timestamp() + REAUTH_TOKEN_SECONDS;
+    let payload = format!("{}.{expires}", user_id.simple());
+    let signature = signature(key, &payload);
+    (format!("{payload}.{signature}"), expires)
+}
+
+// the token is of the user and not expired
+pub fn verify_token(key: &[u8], token: &str, user_id: Uuid) -> bool {
+    let Some((payload, token_signature)) = token.rsplit_once('.') else {
+        return false;
+    };
+    if !constant_time_eq(
+        signature(key, payload).as_bytes(),
+        token_signature.as_bytes(),
+    ) {
+        return false;
+    }
+    let Some((token_user_id, expires)) = payload.split_once('.') else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<i64>() else {
+        return false;
+    };
+    token_user_id == user_id.simple().to_string() && Utc::now().timestamp() < expires
+}
+
+fn signature(key: &[u8], payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+// the signed in user with a valid reauth token for sensitive operations
+// not signed in: 401, missing, expired or foreign token: 403 ReauthRequired
+pub struct ExtractReauth(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ExtractReauth
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ExtractMeEnsure(me) = ExtractMeEnsure::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let app_state = parts.extensions.get::<AppState>().ok_or_else(|| {
+            error!("AppState not found in request extensions, is the Extension layer missing?");
+            WebauthnError::Unknown.into_response()
+        })?;
+        let token = parts
+            .headers
+            .get(REAUTH_TOKEN_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if !verify_token(app_state.reauth_key.as_ref(), token, me.id) {
+            info!("Missing or invalid reauth token of user {}", me.id);
+            return Err(WebauthnError::ReauthRequired.into_response());
+        }
+        Ok(ExtractReauth(me))
+    }
+}
@@ -1,24 +1,51 @@
 use axum::async_trait;
 use axum::{
-    extract::{Extension, Json},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Extension, Json, Query},
+    http::{
+        header::{ACCEPT, AUTHORIZATION, USER_AGENT},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
 };
-use std::env;
 use tower_cookies::Cookies;
-use tower_sessions::Session;
+use tower_sessions::{Expiry, Session, SessionManagerLayer};
+use tower_sessions_rusqlite_store::RusqliteStore;
 
 use chrono::{DateTime, Utc};
 use cookie::time::{Duration, OffsetDateTime};
 use cookie::{Cookie, SameSite};
+use std::sync::atomic::Ordering;
 
+use crate::admin::constant_time_eq;
+use crate::api_token;
+use crate::config::{Config, InfoCookieContents};
+use crate::db::DB;
 use crate::error::WebauthnError;
-use crate::models::User;
+use crate::models::{Authenticator, User};
 use crate::queries;
+use crate::random;
 use crate::state::AppState;
+use crate::ua::user_agent::{get_user_agent_string_short, ParsedUserAgent};
 
 const COOKIE_NAME_JS: &str = "authenticated_user_js";
 
+// session keys, prefixed with SESSION_KEY_PREFIX (see Config::session_key)
+pub const AUTHENTICATED_USER: &str = "authenticated_user";
+pub const LAST_ACTIVITY: &str = "last_activity";
+pub const SIGNED_IN_AT: &str = "signed_in_at";
+// passkey ceremony states, see auth.rs
+pub const REG_STATE: &str = "reg_state";
+// credential id and pending_additional of the last finished registration
+pub const REG_FINISHED: &str = "reg_finished";
+pub const AUTH_STATE: &str = "auth_state";
+pub const AUTH_FOR_STATE: &str = "auth_for_state";
+pub const MERGE_STATE: &str = "merge_state";
+pub const REAUTH_STATE: &str = "reauth_state";
+pub const POW_STATE: &str = "pow_state";
+const SESSION_EXPIRES_HEADER: &str = "x-session-expires";
+// the informative cookie is ~200 bytes, anything larger was tampered with
+const COOKIE_JS_MAX_LEN: usize = 1024;
+
 // remembers the user in the server side session and a cookie for the client
 // the session is used server side
 // the cookie to inform the client app
@@ -26,28 +53,75 @@ pub async fn set_me_authenticated(
     user: User,
     session: Session,
     cookies: Cookies,
+    config: &Config,
 ) -> Result<(), WebauthnError> {
     session
-        .insert("authenticated_user", user.clone())
+        .insert(&config.session_key(AUTHENTICATED_USER), user.clone())
         .await
         .map_err(|e| {
             error!("Failed to insert authenticated_user into session: {:?}", e);
             WebauthnError::CorruptSession
         })?;
+    session
+        .insert(&config.session_key(SIGNED_IN_AT), Utc::now())
+        .await
+        .map_err(|e| {
+            error!("Failed to insert signed_in_at into session: {:?}", e);
+            WebauthnError::CorruptSession
+        })?;
+    // session fixation: an id set before the sign in (e.g. planted by an
+    // attacker) must not be valid afterwards. The data moves to a new id, the
+    // old record is deleted and the cookie updated with the response.
+    session.cycle_id().await.map_err(|e| {
+        error!("Failed to cycle the session id: {:?}", e);
+        WebauthnError::CorruptSession
+    })?;
 
-    cookies.add(create_informative_cookie(user, session.expiry_date()));
+    add_informative_cookie(&cookies, user, session.expiry_date(), config);
     Ok(())
 }
 
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct SignoutResponse {
+    signed_out: bool,
+}
+
 // post signout handler
 // remove session and informative cookie
-pub async fn signout(session: Session, cookies: Cookies) -> Result<(), StatusCode> {
-    session.flush().await.map_err(|e| {
-        error!("Failed to remove authenticated_user from session: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    cookies.remove(Cookie::new(COOKIE_NAME_JS, ""));
-    Ok(())
+// idempotent: signed_out is false if there was no signed in user
+#[utoipa::path(post, path = "/signout", responses(
+    (status = 200, body = SignoutResponse),
+))]
+pub async fn signout(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    cookies: Cookies,
+) -> Result<impl IntoResponse, StatusCode> {
+    let signed_out = current_user(&session, &app_state.config).await.is_some();
+
+    // nothing to flush for an empty session, e.g. when called twice
+    if !session.is_empty().await {
+        session.flush().await.map_err(|e| {
+            error!("Failed to remove authenticated_user from session: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    cookies.remove(informative_cookie_removal(&app_state.config));
+    Ok(Json(SignoutResponse { signed_out }))
+}
+
+// adds the informative cookie, unless disabled with DISABLE_INFO_COOKIE
+// without the cookie the client must call /me on load, the expiry is sent in
+// the X-Session-Expires header of api responses (see roll_expiry_mw)
+fn add_informative_cookie(
+    cookies: &Cookies,
+    user: User,
+    expiry_date: OffsetDateTime,
+    config: &Config,
+) {
+    if config.info_cookie_enabled {
+        cookies.add(create_informative_cookie(user, expiry_date, config));
+    }
 }
 
 // informative cookie for the client app
@@ -56,25 +130,50 @@ pub async fn signout(session: Session, cookies: Cookies) -> Result<(), StatusCod
 //   used to know when to refresh the session
 // informative: only used to render the ui, not used for authentication
 // see AuthContext.tsx for the client side code
-fn create_informative_cookie(user: User, expiry_date: OffsetDateTime) -> Cookie<'static> {
+fn create_informative_cookie(
+    user: User,
+    expiry_date: OffsetDateTime,
+    config: &Config,
+) -> Cookie<'static> {
     let expiry_date = expiry_date - Duration::seconds(1);
 
-    #[derive(serde::Serialize)]
-    struct CookiePayload {
-        user: User,
-        #[serde(with = "time::serde::rfc3339")]
-        expiry_date: OffsetDateTime,
-    }
-
+    // INFO_COOKIE_CONTENTS=expiry_only: no user, the client gets it from /me
+    let user = (config.info_cookie_contents == InfoCookieContents::Full).then_some(user);
     let payload = serde_json::to_string(&CookiePayload { user, expiry_date }).unwrap();
 
-    Cookie::build((COOKIE_NAME_JS, payload))
+    let mut cookie = Cookie::build((COOKIE_NAME_JS, payload))
         .path("/")
         .expires(expiry_date)
         .http_only(false)
         .same_site(SameSite::Strict)
-        .secure(env::var("COOKIES_SECURE").unwrap_or("true".to_string()) != "false")
-        .build()
+        .secure(config.cookies_secure)
+        .build();
+    if let Some(domain) = &config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+// a removal only matches the cookie with the same path and domain
+fn informative_cookie_removal(config: &Config) -> Cookie<'static> {
+    let mut cookie = Cookie::build((COOKIE_NAME_JS, "")).path("/").build();
+    if let Some(domain) = &config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CookiePayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<User>,
+    #[serde(with = "time::serde::rfc3339")]
+    expiry_date: OffsetDateTime,
+}
+
+// the cookie is client controlled, don't trust a cookie we wouldn't have set
+fn is_valid_informative_cookie(value: &str) -> bool {
+    value.len() <= COOKIE_JS_MAX_LEN && serde_json::from_str::<CookiePayload>(value).is_ok()
 }
 
 // not called for auth routes ⬆️
@@ -82,18 +181,29 @@ fn create_informative_cookie(user: User, expiry_date: OffsetDateTime) -> Cookie<
 // roll the session and cookie expiry date
 const ROLL_SESSION_EVERY_SECONDS: i64 = 60;
 pub async fn roll_expiry_mw(
+    Extension(app_state): Extension<AppState>,
     cookies: Cookies,
     session: Session,
     request: axum::extract::Request,
     next: axum::middleware::Next,
 ) -> axum::response::Response {
-    let response = next.run(request).await;
+    let mut response = next.run(request).await;
+
+    let me = current_user(&session, &app_state.config).await;
 
-    let me = get_me_from_session(session.clone()).await;
+    // garbage or oversized informative cookie: clear it (and set a valid one below)
+    let cookie_is_invalid = cookies
+        .get(COOKIE_NAME_JS)
+        .is_some_and(|c| !is_valid_informative_cookie(c.value()));
+    if cookie_is_invalid {
+        info!("invalid informative cookie, clearing it");
+        cookies.remove(informative_cookie_removal(&app_state.config));
+    }
 
     if me.is_some() {
         let now = chrono::Utc::now();
-        let last_activity: Option<DateTime<Utc>> = session.get("last_activity").await.unwrap();
+        let last_activity_key = app_state.config.session_key(LAST_ACTIVITY);
+        let last_activity: Option<DateTime<Utc>> = session.get(&last_activity_key).await.unwrap();
         let do_roll = match last_activity {
             Some(last_activity) => (now - last_activity).num_seconds() > ROLL_SESSION_EVERY_SECONDS,
             None => true,
@@ -102,29 +212,173 @@ pub async fn roll_expiry_mw(
             // don't touch authenticated_user!
             // the expiry for the complete session (including authenticated_user)
             // is extended when last_activity is updated
-            session.insert("last_activity", now).await.unwrap();
+            session.insert(&last_activity_key, now).await.unwrap();
+        }
+        if do_roll || cookie_is_invalid {
             // sync informative cookie
-            cookies.add(create_informative_cookie(
+            add_informative_cookie(
+                &cookies,
                 me.unwrap(),
                 session.expiry_date(),
-            ));
+                &app_state.config,
+            );
+        }
+        // for clients without the informative cookie
+        let expiry_date =
+            DateTime::<Utc>::from_timestamp(session.expiry_date().unix_timestamp(), 0)
+                .map(|d| d.to_rfc3339())
+                .unwrap_or_default();
+        if let Ok(value) = HeaderValue::from_str(&expiry_date) {
+            response.headers_mut().insert(SESSION_EXPIRES_HEADER, value);
         }
     } else if cookies.get(COOKIE_NAME_JS).is_some() {
         info!("cookie found, but no user in session");
-        cookies.remove(Cookie::new(COOKIE_NAME_JS, ""));
+        cookies.remove(informative_cookie_removal(&app_state.config));
     }
 
     response
 }
 
-// get me from session
-async fn get_me_from_session(session: Session) -> Option<User> {
+// periodically delete expired sessions
+// replaces ExpiredDeletion::continuously_delete_expired of the store
+// to be able to log how many sessions were deleted in each sweep.
+// never exits: failed sweeps are logged and retried in the next one. The time
+// of the last successful sweep is recorded for /health (see get_health).
+// jitter: random delay of up to jitter before the first sweep and added to
+// each period, so instances sharing a store don't sweep in lockstep. 0: a
+// sweep at startup and every period exactly.
+pub async fn continuously_delete_expired(
+    app_state: AppState,
+    period: tokio::time::Duration,
+    jitter: tokio::time::Duration,
+) {
+    tokio::time::sleep(random_jitter(jitter)).await;
+    loop {
+        let deleted = match delete_expired_in_chunks(&app_state.db).await {
+            Ok(deleted) => deleted,
+            Err(e) => {
+                error!("Failed to delete expired sessions: {:?}", e);
+                tokio::time::sleep(period + random_jitter(jitter)).await;
+                continue;
+            }
+        };
+        app_state
+            .last_session_sweep
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+        if deleted > 0 {
+            info!("Deleted {deleted} expired sessions");
+        } else {
+            debug!("No expired sessions to delete");
+        }
+        tokio::time::sleep(period + random_jitter(jitter)).await;
+    }
+}
+
+// uniform in 0..=max, millisecond resolution
+fn random_jitter(max: tokio::time::Duration) -> tokio::time::Duration {
+    let max_ms = max.as_millis() as u64;
+    if max_ms == 0 {
+        return tokio::time::Duration::ZERO;
+    }
+    tokio::time::Duration::from_millis(random::random_u64() % (max_ms + 1))
+}
+
+// sessions deleted per db call of a sweep
+const SESSION_CLEANUP_CHUNK: usize = 500;
+
+// background calls of at most SESSION_CLEANUP_CHUNK sessions each, auth calls
+// run in between (see DB::background_call)
+async fn delete_expired_in_chunks(db: &DB) -> tokio_rusqlite::Result<usize> {
+    let mut deleted = 0;
+    loop {
+        let chunk = db
+            .background_call("delete_expired_sessions", |conn| {
+                queries::delete_expired_sessions(conn, SESSION_CLEANUP_CHUNK).map_err(|e| e.into())
+            })
+            .await?;
+        deleted += chunk;
+        if chunk < SESSION_CLEANUP_CHUNK {
+            return Ok(deleted);
+        }
+    }
+}
+
+// health check, includes the background session cleanup
+// 503 if the last successful sweep is older than 3 intervals (with jitter),
+// e.g. the store errors repeatedly.
+pub async fn get_health(Extension(app_state): Extension<AppState>) -> impl IntoResponse {
+    let last_sweep = app_state.last_session_sweep.load(Ordering::Relaxed);
+    let config = &app_state.config;
+    let max_age =
+        3 * (config.session_cleanup_interval + config.session_cleanup_jitter).as_secs() as i64;
+    let sweep_is_stale = Utc::now().timestamp() - last_sweep > max_age;
+    let status = if sweep_is_stale {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    let body = serde_json::json!({
+        "status": if sweep_is_stale { "session_cleanup_stale" } else { "ok" },
+        "last_session_sweep": DateTime::<Utc>::from_timestamp(last_sweep, 0),
+    });
+    (status, Json(body))
+}
+
+// sessions expire after this long without a (rolled) request
+pub const SESSION_INACTIVITY_HOURS: i64 = 1;
+
+// session layer shared by all routers
+// expiry is rolled on requests, see roll_expiry_mw
+pub fn session_layer(
+    session_store: RusqliteStore,
+    config: &Config,
+) -> SessionManagerLayer<RusqliteStore> {
+    let layer = SessionManagerLayer::new(session_store)
+        .with_name(&config.session_name)
+        .with_same_site(SameSite::Strict)
+        .with_secure(config.cookies_secure)
+        .with_expiry(Expiry::OnInactivity(Duration::hours(
+            SESSION_INACTIVITY_HOURS,
+        )));
+    match &config.cookie_domain {
+        Some(domain) => layer.with_domain(domain.clone()),
+        None => layer,
+    }
+}
+
+// the signed in user of the session, None if anonymous (or the session can't
+// be read). Shared by the extractors and handlers taking the Session directly.
+// From the session alone, no db call: the sessions of deleted (or merged-away)
+// users are deleted with them, see queries::delete_sessions_of_user.
+pub async fn current_user(session: &Session, config: &Config) -> Option<User> {
     session
-        .get::<User>("authenticated_user")
+        .get::<User>(&config.session_key(AUTHENTICATED_USER))
         .await
         .unwrap_or(None)
 }
 
+// the Session is put into the request extensions by the session layer
+// everything using ExtractMe/ExtractMeEnsure (including test harnesses) needs
+// session_layer with a migrated store and the CookieManagerLayer around it.
+fn session_from_parts(
+    parts: &axum::http::request::Parts,
+) -> Result<&Session, (StatusCode, &'static str)> {
+    parts.extensions.get::<Session>().ok_or_else(|| {
+        error!("Session not found in request extensions, is the session layer missing?");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Session layer missing")
+    })
+}
+
+// AppState is put into the request extensions by the Extension layer
+fn app_state_from_parts(
+    parts: &axum::http::request::Parts,
+) -> Result<&AppState, (StatusCode, &'static str)> {
+    parts.extensions.get::<AppState>().ok_or_else(|| {
+        error!("AppState not found in request extensions, is the Extension layer missing?");
+        (StatusCode::INTERNAL_SERVER_ERROR, "AppState missing")
+    })
+}
+
 pub struct ExtractMe(pub Option<User>);
 
 #[async_trait]
@@ -138,12 +392,22 @@ where
         parts: &mut axum::http::request::Parts,
         _state: &S,
     ) -> Result<Self, Self::Rejection> {
-        let session = parts.extensions.get::<tower_sessions::Session>().unwrap();
-        let me = get_me_from_session(session.clone()).await;
+        let session = session_from_parts(parts)?;
+        let app_state = app_state_from_parts(parts)?;
+        let me = current_user(session, &app_state.config).await;
         Ok(ExtractMe(me))
     }
 }
 
+// 401 of the websocket upgrades (/chat, /events), json like the api 404s (see
+// handler_404). Browsers don't expose it to js, but other clients and the
+// devtools get a reason instead of the plain text of ExtractMeEnsure.
+#[cfg_attr(not(any(feature = "chat", feature = "events")), allow(dead_code))]
+pub fn unauthorized_response() -> axum::response::Response {
+    let body = serde_json::json!({ "error": "unauthorized" });
+    (StatusCode::UNAUTHORIZED, Json(body)).into_response()
+}
+
 pub struct ExtractMeEnsure(pub User);
 
 #[async_trait]
@@ -157,8 +421,9 @@ where
         parts: &mut axum::http::request::Parts,
         _state: &S,
     ) -> Result<Self, Self::Rejection> {
-        let session = parts.extensions.get::<tower_sessions::Session>().unwrap();
-        let me = get_me_from_session(session.clone()).await;
+        let session = session_from_parts(parts)?;
+        let app_state = app_state_from_parts(parts)?;
+        let me = current_user(session, &app_state.config).await;
         match me {
             Some(me) => Ok(ExtractMeEnsure(me)),
             None => Err((StatusCode::UNAUTHORIZED, "Unauthorized")),
@@ -166,30 +431,523 @@ where
     }
 }
 
+// the user of `Authorization: Bearer <token>`, else the user of the session
+// like ExtractMe. For server to server and cli use. Tokens are per user api
+// tokens (pat_..., see api_token.rs) or API_TOKEN (user API_TOKEN_USER_ID).
+// precedence: a present Bearer token wins, the cookie is not looked at then,
+// and a wrong token is a 401 instead of falling back to the session.
+pub struct ExtractMeOrToken(pub Option<User>);
+
+#[async_trait]
+impl<S> axum::extract::FromRequestParts<S> for ExtractMeOrToken
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let app_state = app_state_from_parts(parts)?;
+        let Some(token) = bearer_token(parts) else {
+            let session = session_from_parts(parts)?;
+            return Ok(ExtractMeOrToken(
+                current_user(session, &app_state.config).await,
+            ));
+        };
+        if token.starts_with(api_token::TOKEN_PREFIX) {
+            return match api_token::user_for_token(app_state, &token).await {
+                Ok(Some(user)) => Ok(ExtractMeOrToken(Some(user))),
+                Ok(None) => {
+                    warn!("Request with invalid api token");
+                    Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+                }
+                Err(e) => Err((e.status_code(), "Database error")),
+            };
+        }
+        let user_id = match &app_state.config.api_token {
+            Some((api_token, user_id))
+                if constant_time_eq(token.as_bytes(), api_token.as_bytes()) =>
+            {
+                *user_id
+            }
+            _ => {
+                warn!("Request with invalid api token");
+                return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
+            }
+        };
+        // a deleted user's token doesn't sign in anymore
+        let user = app_state
+            .db
+            .timed_call("get_user_by_id", move |conn| {
+                queries::get_user_by_id(conn, user_id).map_err(|e| e.into())
+            })
+            .await
+            .map_err(|e| match e {
+                tokio_rusqlite::Error::Rusqlite(rusqlite::Error::QueryReturnedNoRows) => {
+                    warn!("Api token user {user_id} not found");
+                    (StatusCode::UNAUTHORIZED, "Unauthorized")
+                }
+                e => {
+                    error!("get_user_by_id: {:?}", e);
+                    (WebauthnError::from_db(&e).status_code(), "Database error")
+                }
+            })?;
+        Ok(ExtractMeOrToken(Some(user)))
+    }
+}
+
+// None for other schemes (e.g. Basic auth of a proxy), the session is used then
+fn bearer_token(parts: &axum::http::request::Parts) -> Option<String> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string)
+}
+
 // rest handlers
 
+#[derive(Debug, serde::Deserialize)]
+pub struct MeParams {
+    // comma separated, e.g. ?include=authenticators_count
+    include: Option<String>,
+}
+
+// the user, plus the optional includes
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct MeResponse {
+    #[serde(flatten)]
+    user: User,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authenticators_count: Option<i64>,
+}
+
+// the session check of the client, without includes it's answered from the
+// session alone (no db call). include=authenticators_count costs one count
+// query, a scan of the authenticators table (there is no index on user_id),
+// it saves the /me/authenticators round trip for the account header.
+#[utoipa::path(get, path = "/me",
+    params(("include" = Option<String>, Query, description = "authenticators_count")),
+    responses(
+        (status = 200, body = MeResponse),
+        (status = 401, description = "Not signed in"),
+    )
+)]
 pub async fn get_me(
+    Extension(app_state): Extension<AppState>,
+    ExtractMeOrToken(user): ExtractMeOrToken,
+    Query(params): Query<MeParams>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let user = user.ok_or(StatusCode::UNAUTHORIZED)?;
+    let include = params.include.unwrap_or_default();
+    let authenticators_count = if include
+        .split(',')
+        .any(|i| i.trim() == "authenticators_count")
+    {
+        let user_id = user.id;
+        let count = app_state
+            .db
+            .timed_call("count_authenticators_for_user_id", move |conn| {
+                queries::count_authenticators_for_user_id(conn, user_id).map_err(|e| e.into())
+            })
+            .await
+            .map_err(|e| {
+                error!("count_authenticators_for_user_id: {:?}", e);
+                WebauthnError::from_db(&e).status_code()
+            })?;
+        Some(count)
+    } else {
+        None
+    };
+    if prefers_html(&headers) {
+        let mut rows = vec![
+            vec!["id".to_string(), user.id.to_string()],
+            vec!["username".to_string(), user.username.clone()],
+            vec!["created_at".to_string(), user.created_at.to_rfc3339()],
+        ];
+        if let Some(count) = authenticators_count {
+            rows.push(vec!["authenticators_count".to_string(), count.to_string()]);
+        }
+        return Ok(html_table("/me", &["field", "value"], rows).into_response());
+    }
+    Ok(Json(MeResponse {
+        user,
+        authenticators_count,
+    })
+    .into_response())
+}
+
+// re-issue the informative cookie right away, e.g. if the client lost it
+// while the session is still valid (instead of waiting for roll_expiry_mw)
+#[utoipa::path(post, path = "/me/refresh_cookie", responses(
+    (status = 200, body = User),
+    (status = 401, description = "Not signed in"),
+))]
+pub async fn refresh_cookie(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    cookies: Cookies,
     ExtractMeEnsure(user): ExtractMeEnsure,
 ) -> Result<impl IntoResponse, StatusCode> {
+    add_informative_cookie(
+        &cookies,
+        user.clone(),
+        session.expiry_date(),
+        &app_state.config,
+    );
     Ok(Json(user))
 }
 
+#[utoipa::path(get, path = "/me/authenticators", responses(
+    (status = 200, body = Vec<Authenticator>),
+    (status = 401, description = "Not signed in"),
+))]
 pub async fn get_my_authenticators(
     Extension(app_state): Extension<AppState>,
-    ExtractMeEnsure(user): ExtractMeEnsure,
-) -> Result<impl IntoResponse, StatusCode> {
+    ExtractMeOrToken(user): ExtractMeOrToken,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let user = user.ok_or(StatusCode::UNAUTHORIZED)?;
     let authenticators = app_state
         .db
-        .conn
-        .call(move |conn| {
+        .timed_call("get_authenticators_for_user_id", move |conn| {
             queries::get_authenticators_for_user_id(conn, user.id).map_err(|e| e.into())
         })
         .await
         .map_err(|e| {
             error!("get_authenticators_for_user: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            WebauthnError::from_db(&e).status_code()
         })?;
-    Ok(Json(authenticators))
+    if prefers_html(&headers) {
+        let rows = authenticators
+            .iter()
+            .map(|a| {
+                vec![
+                    a.nickname.clone().unwrap_or_default(),
+                    a.user_agent_short.clone(),
+                    a.created_at.to_rfc3339(),
+                    a.backup_state.to_string(),
+                    a.counter.to_string(),
+                    a.transports.join(", "),
+                ]
+            })
+            .collect();
+        let columns = [
+            "nickname",
+            "user agent",
+            "created_at",
+            "synced",
+            "counter",
+            "transports",
+        ];
+        return Ok(html_table("/me/authenticators", &columns, rows).into_response());
+    }
+    Ok(Json(authenticators).into_response())
+}
+
+// the current session only. created_at: the sign in (SIGNED_IN_AT), null for
+// sessions signed in before it was recorded. device: of this request's
+// User-Agent, which is the device of the session.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct SessionInfo {
+    created_at: Option<DateTime<Utc>>,
+    // last rolled, see roll_expiry_mw
+    last_activity: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    device: String,
+}
+
+// session only, bearer tokens have no session
+#[utoipa::path(get, path = "/me/session_info", responses(
+    (status = 200, body = SessionInfo),
+    (status = 401, description = "Not signed in"),
+))]
+pub async fn get_session_info(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    ExtractMeEnsure(_me): ExtractMeEnsure,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let config = &app_state.config;
+    let created_at = session.get(&config.session_key(SIGNED_IN_AT)).await?;
+    let last_activity = session.get(&config.session_key(LAST_ACTIVITY)).await?;
+    let expires_at = DateTime::<Utc>::from_timestamp(session.expiry_date().unix_timestamp(), 0);
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|ua| ParsedUserAgent::parse(ua, &app_state.ua_parser));
+    Ok(Json(SessionInfo {
+        created_at,
+        last_activity,
+        expires_at,
+        device: get_user_agent_string_short(user_agent.as_ref()),
+    }))
+}
+
+// browsers asking for a page (`Accept: text/html,...`), e.g. opening /me while
+// signed in to debug a session. json stays the default, also for */* and
+// clients listing json before html.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    match (accept.find("text/html"), accept.find("application/json")) {
+        (Some(html), Some(json)) => html < json,
+        (html, _) => html.is_some(),
+    }
+}
+
+// minimal page for prefers_html, no styles, no scripts
+fn html_table(title: &str, columns: &[&str], rows: Vec<Vec<String>>) -> Html<String> {
+    let row = |cells: Vec<String>, tag: &str| {
+        let cells: String = cells
+            .iter()
+            .map(|c| format!("<{tag}>{}</{tag}>", escape_html(c)))
+            .collect();
+        format!("<tr>{cells}</tr>")
+    };
+    let head = row(columns.iter().map(|c| c.to_string()).collect(), "th");
+    let body: String = rows.into_iter().map(|r| row(r, "td")).collect();
+    Html(format!(
+        "<!doctype html><meta charset=\"utf-8\"><title>{title}</title>\
+        <h1>{title}</h1><table border=\"1\">{head}{body}</table>"
+    ))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
 
 // for graphql handlers see graphql.rs
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use axum::{
+        body::Body,
+        http::{
+            header::{COOKIE, SET_COOKIE},
+            Request,
+        },
+        routing::post,
+        Router,
+    };
+    use std::sync::Arc;
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    async fn session_store() -> RusqliteStore {
+        let conn = tokio_rusqlite::Connection::open_in_memory().await.unwrap();
+        let store = RusqliteStore::new(conn);
+        store.migrate().await.unwrap();
+        store
+    }
+
+    #[test]
+    fn informative_cookie_of_the_server_is_valid() {
+        let expiry_date = OffsetDateTime::now_utc() + Duration::days(1);
+        for info_cookie_contents in [InfoCookieContents::Full, InfoCookieContents::ExpiryOnly] {
+            let config = Config {
+                info_cookie_contents,
+                ..test_config()
+            };
+            let cookie =
+                create_informative_cookie(User::new("patte".to_string()), expiry_date, &config);
+            assert!(is_valid_informative_cookie(cookie.value()));
+        }
+    }
+
+    #[test]
+    fn garbage_informative_cookie_is_invalid() {
+        for value in [
+            "",
+            "garbage",
+            "{",
+            "null",
+            "[]",
+            "{\"user\":{\"id\":\"not a uuid\"}}",
+            // the expiry date is required
+            "{\"user\":null}",
+            "{\"expiry_date\":\"tomorrow\"}",
+        ] {
+            assert!(!is_valid_informative_cookie(value), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn oversized_informative_cookie_is_invalid() {
+        let expiry_date = OffsetDateTime::now_utc() + Duration::days(1);
+        let cookie =
+            create_informative_cookie(User::new("patte".to_string()), expiry_date, &test_config());
+        let padded = format!(
+            "{}{}",
+            cookie.value(),
+            " ".repeat(COOKIE_JS_MAX_LEN - cookie.value().len() + 1)
+        );
+        // still valid json, but too large
+        assert!(serde_json::from_str::<CookiePayload>(&padded).is_ok());
+        assert!(!is_valid_informative_cookie(&padded));
+    }
+
+    #[tokio::test]
+    async fn prefixed_session_keys_round_trip() {
+        let config = Config {
+            session_key_prefix: "myapp:".to_string(),
+            ..test_config()
+        };
+        assert_eq!(config.session_key(REG_STATE), "myapp:reg_state");
+
+        let store = Arc::new(session_store().await);
+        let session = Session::new(None, store.clone(), None);
+        session
+            .insert(&config.session_key(REG_STATE), 42)
+            .await
+            .unwrap();
+        // the host app's key of the same name is another value
+        session.insert(REG_STATE, "host").await.unwrap();
+        session.save().await.unwrap();
+
+        let loaded = Session::new(session.id(), store, None);
+        assert_eq!(
+            loaded
+                .get::<i32>(&config.session_key(REG_STATE))
+                .await
+                .unwrap(),
+            Some(42)
+        );
+        assert_eq!(
+            loaded.get::<String>(REG_STATE).await.unwrap().as_deref(),
+            Some("host")
+        );
+        assert_eq!(
+            loaded
+                .get::<i32>(&config.session_key(AUTH_STATE))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    // value of the session cookie set by the response
+    fn session_cookie(response: &Response, config: &Config) -> Option<String> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| Cookie::parse(v.to_str().ok()?.to_string()).ok())
+            .find(|c| c.name() == config.session_name)
+            .map(|c| c.value().to_string())
+    }
+
+    async fn request(router: &Router, path: &str, session_id: Option<&str>) -> Response {
+        let config = test_config();
+        let mut request = Request::post(path);
+        if let Some(session_id) = session_id {
+            request = request.header(COOKIE, format!("{}={session_id}", config.session_name));
+        }
+        router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sign_in_cycles_the_session_id() {
+        let config = test_config();
+        let router = Router::new()
+            // e.g. a started ceremony, or a session id planted by an attacker
+            .route(
+                "/anonymous",
+                post(|session: Session| async move {
+                    session.insert("visited", true).await.unwrap();
+                }),
+            )
+            .route(
+                "/sign_in",
+                post(|session: Session, cookies: Cookies| async move {
+                    let user = User::new("patte".to_string());
+                    set_me_authenticated(user, session, cookies, &test_config()).await
+                }),
+            )
+            .route(
+                "/signed_in",
+                post(|session: Session| async move {
+                    let key = test_config().session_key(AUTHENTICATED_USER);
+                    session
+                        .get::<User>(&key)
+                        .await
+                        .unwrap()
+                        .is_some()
+                        .to_string()
+                }),
+            )
+            .layer(session_layer(session_store().await, &config))
+            .layer(CookieManagerLayer::new());
+
+        let response = request(&router, "/anonymous", None).await;
+        let anonymous_id = session_cookie(&response, &config).expect("session cookie");
+
+        let response = request(&router, "/sign_in", Some(&anonymous_id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let signed_in_id = session_cookie(&response, &config).expect("session cookie");
+        assert_ne!(anonymous_id, signed_in_id);
+
+        // only the new id is signed in
+        for (session_id, signed_in) in [(anonymous_id, "false"), (signed_in_id, "true")] {
+            let response = request(&router, "/signed_in", Some(&session_id)).await;
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], signed_in.as_bytes());
+        }
+    }
+
+    fn parts_with_authorization(value: &str) -> axum::http::request::Parts {
+        let (parts, _) = Request::get("/me")
+            .header(AUTHORIZATION, value)
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    #[test]
+    fn bearer_token_only_of_the_bearer_scheme() {
+        assert_eq!(
+            bearer_token(&parts_with_authorization("Bearer pat_abc")).as_deref(),
+            Some("pat_abc")
+        );
+        for value in ["Basic dXNlcjpwYXNz", "pat_abc", ""] {
+            assert_eq!(
+                bearer_token(&parts_with_authorization(value)),
+                None,
+                "{value:?}"
+            );
+        }
+        let (parts, _) = Request::get("/me").body(()).unwrap().into_parts();
+        assert_eq!(bearer_token(&parts), None);
+    }
+
+    #[test]
+    fn random_jitter_within_max() {
+        let max = tokio::time::Duration::from_millis(20);
+        for _ in 0..100 {
+            assert!(random_jitter(max) <= max);
+        }
+        assert_eq!(
+            random_jitter(tokio::time::Duration::ZERO),
+            tokio::time::Duration::ZERO
+        );
+    }
+}
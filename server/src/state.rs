@@ -1,5 +1,17 @@
-use std::env;
-use std::sync::Arc;
+use std::sync::{atomic::AtomicI64, Arc};
+#[cfg(feature = "chat")]
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Mutex,
+    },
+};
+#[cfg(feature = "chat")]
+use tokio::sync::broadcast;
+
+#[cfg(feature = "chat")]
+use crate::chat::{ChatMessage, LiveConnection};
 use uaparser::UserAgentParser;
 use webauthn_rs::prelude::*;
 
@@ -7,7 +19,11 @@ use webauthn_rs::prelude::*;
  * server side app state and setup
  */
 
+use crate::config::Config;
 use crate::db::DB;
+use crate::events::EventHub;
+use crate::random;
+use crate::reauth::REAUTH_KEY_LEN;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -16,40 +32,106 @@ pub struct AppState {
     // lifetimes.
     pub webauthn: Arc<Webauthn>,
     pub db: DB,
+    pub config: Arc<Config>,
     pub ua_parser: Arc<UserAgentParser>,
+    // per user auth and session events, see events.rs
+    pub events: EventHub,
+    // unix timestamp of the last successful expired session cleanup
+    // (startup until the first sweep), see continuously_delete_expired
+    pub last_session_sweep: Arc<AtomicI64>,
+    // signs the reauth tokens, see reauth.rs
+    pub reauth_key: Arc<[u8; REAUTH_KEY_LEN]>,
+    // chat: channel to all connections, connections per username, replay buffer
+    #[cfg(feature = "chat")]
+    pub tx: broadcast::Sender<ChatMessage>,
+    #[cfg(feature = "chat")]
+    pub connected_usernames: Arc<Mutex<HashMap<String, usize>>>,
+    #[cfg(feature = "chat")]
+    pub recent_messages: Arc<Mutex<VecDeque<ChatMessage>>>,
+    #[cfg(feature = "chat")]
+    pub next_message_id: Arc<AtomicU64>,
+    // registry of the chat connections by id, see reap_presence
+    #[cfg(feature = "chat")]
+    pub live_connections: Arc<Mutex<HashMap<u64, LiveConnection>>>,
+    #[cfg(feature = "chat")]
+    pub next_connection_id: Arc<AtomicU64>,
+    // open chat websockets, limited by CHAT_MAX_CONNECTIONS
+    #[cfg(feature = "chat")]
+    pub chat_connections: Arc<AtomicUsize>,
+    // unix timestamp of the last announcement, see announce_handler
+    #[cfg(feature = "chat")]
+    pub last_announcement: Arc<AtomicI64>,
 }
 
 impl AppState {
-    pub async fn new() -> Self {
-        // Effective domain name. Ff changed, all credentials are invalidated!!
-        let rp_id = env::var("RP_ID").expect("RP_ID environment variable not set");
-
-        // Url containing the effective domain name
-        // MUST include the port number!
-        let rp_origin =
-            Url::parse(&env::var("RP_ORIGIN").expect("RP_ORIGIN environment variable not set"))
-                .expect("Invalid URL");
+    pub async fn new(config: Config) -> Self {
+        // webauthn needs a secure context, browsers only treat http as secure
+        // for localhost. Registration would fail with opaque errors.
+        let origin_host = config.rp_origin.host_str().unwrap_or("");
+        if config.rp_origin.scheme() == "http"
+            && !["localhost", "127.0.0.1", "[::1]"].contains(&origin_host)
+        {
+            if config.rp_origin_strict {
+                panic!("Insecure RP_ORIGIN {}, use https", config.rp_origin);
+            }
+            warn!(
+                "RP_ORIGIN {} uses http, webauthn will fail in browsers! Use https.",
+                config.rp_origin
+            );
+        }
 
-        let builder = WebauthnBuilder::new(&rp_id, &rp_origin).expect("Invalid configuration");
+        // Effective domain name (rp_id) and url containing it (rp_origin)
+        // see Config
+        let builder = WebauthnBuilder::new(&config.rp_id, &config.rp_origin)
+            .expect("Invalid RP_ID or RP_ORIGIN, RP_ORIGIN must contain RP_ID");
 
         // Set a "nice" relying party name. Has no security properties and
         // may be changed in the future.
-        let rp_name = env::var("RP_NAME").expect("RP_NAME environment variable not set");
-        let builder = builder.rp_name(&rp_name);
+        let builder = builder.rp_name(&config.rp_name);
+
+        // the builder has no setting for credential algorithms, the allowed ones
+        // (WEBAUTHN_ALGORITHMS) are applied in start_register and finish_register
 
         // Consume the builder and create our webauthn instance.
-        let webauthn = Arc::new(builder.build().expect("Invalid configuration"));
+        let webauthn = Arc::new(builder.build().expect("Invalid RP configuration"));
 
         // db
-        let db = DB::new().await;
+        let db = DB::new(&config).await;
 
         // useragent parser
         let parser = crate::ua::user_agent::build_parser();
 
+        // reauth tokens
+        let reauth_key: [u8; REAUTH_KEY_LEN] = random::random_bytes();
+
+        // chat
+        #[cfg(feature = "chat")]
+        let (tx, _rx) = broadcast::channel(config.chat_broadcast_capacity);
+
         AppState {
             webauthn,
             db,
+            config: Arc::new(config),
             ua_parser: Arc::new(parser),
+            events: EventHub::default(),
+            last_session_sweep: Arc::new(AtomicI64::new(chrono::Utc::now().timestamp())),
+            reauth_key: Arc::new(reauth_key),
+            #[cfg(feature = "chat")]
+            tx,
+            #[cfg(feature = "chat")]
+            connected_usernames: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "chat")]
+            recent_messages: Arc::new(Mutex::new(VecDeque::new())),
+            #[cfg(feature = "chat")]
+            next_message_id: Arc::new(AtomicU64::new(crate::chat::initial_message_id())),
+            #[cfg(feature = "chat")]
+            live_connections: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "chat")]
+            next_connection_id: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "chat")]
+            chat_connections: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "chat")]
+            last_announcement: Arc::new(AtomicI64::new(0)),
         }
     }
 }
@@ -0,0 +1,163 @@
+use axum::{
+    extract::{Extension, Json},
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use tower_sessions::{Expiry, Session};
+
+use crate::{session::LAST_ACTIVITY, state::AppState};
+
+/*
+ * test only routes (`test_hooks` feature) to inspect and manipulate the
+ * timing of the current session, e.g. to test roll_expiry_mw without
+ * sleeping ROLL_SESSION_EVERY_SECONDS. Not compiled in without the feature,
+ * never enable it in a deployed build.
+ *
+ * not behind roll_expiry_mw, it would roll the manipulated session right away.
+ */
+
+pub fn router() -> Router {
+    warn!("test_hooks enabled: /test_hooks/session can manipulate sessions");
+    Router::new().route(
+        "/test_hooks/session",
+        get(get_session_timing).post(set_session_timing),
+    )
+}
+
+#[derive(serde::Serialize)]
+struct SessionTiming {
+    last_activity: Option<DateTime<Utc>>,
+    expiry_date: Option<DateTime<Utc>>,
+}
+
+async fn get_session_timing(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+) -> Json<SessionTiming> {
+    Json(session_timing(&app_state, &session).await)
+}
+
+#[derive(serde::Deserialize)]
+struct SetSessionTiming {
+    // moves last_activity back, e.g. 61 to make the next api request roll
+    last_activity_ago_seconds: Option<i64>,
+    // fixed expiry instead of the inactivity based one, 0 expires right away
+    expires_in_seconds: Option<i64>,
+}
+
+async fn set_session_timing(
+    Extension(app_state): Extension<AppState>,
+    session: Session,
+    Json(params): Json<SetSessionTiming>,
+) -> Json<SessionTiming> {
+    if let Some(seconds) = params.last_activity_ago_seconds {
+        let last_activity = Utc::now() - chrono::Duration::seconds(seconds);
+        session
+            .insert(&app_state.config.session_key(LAST_ACTIVITY), last_activity)
+            .await
+            .unwrap();
+    }
+    if let Some(seconds) = params.expires_in_seconds {
+        let expiry =
+            cookie::time::OffsetDateTime::now_utc() + cookie::time::Duration::seconds(seconds);
+        session.set_expiry(Some(Expiry::AtDateTime(expiry)));
+    }
+    Json(session_timing(&app_state, &session).await)
+}
+
+async fn session_timing(app_state: &AppState, session: &Session) -> SessionTiming {
+    SessionTiming {
+        last_activity: session
+            .get(&app_state.config.session_key(LAST_ACTIVITY))
+            .await
+            .unwrap_or(None),
+        expiry_date: DateTime::<Utc>::from_timestamp(session.expiry_date().unix_timestamp(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::test_config, session::session_layer};
+    use axum::{
+        body::Body,
+        http::{
+            header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+            Request,
+        },
+        response::Response,
+    };
+    use cookie::Cookie;
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+    use tower_sessions_rusqlite_store::RusqliteStore;
+
+    async fn test_router() -> Router {
+        let app_state = AppState::new(test_config()).await;
+        let session_store = RusqliteStore::new(app_state.db.conn.clone());
+        session_store.migrate().await.unwrap();
+        router()
+            .layer(session_layer(session_store, &app_state.config))
+            .layer(CookieManagerLayer::new())
+            .layer(Extension(app_state))
+    }
+
+    async fn response_timing(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn timestamp(value: &serde_json::Value) -> i64 {
+        DateTime::parse_from_rfc3339(value.as_str().unwrap())
+            .unwrap()
+            .timestamp()
+    }
+
+    #[tokio::test]
+    async fn fast_forward_session() {
+        let router = test_router().await;
+        let now = Utc::now().timestamp();
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::post("/test_hooks/session")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{"last_activity_ago_seconds":61,"expires_in_seconds":30}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_cookie = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| Cookie::parse(v.to_str().ok()?.to_string()).ok())
+            .find(|c| c.name() == test_config().session_name)
+            .expect("session cookie");
+        let timing = response_timing(response).await;
+        assert!((timestamp(&timing["last_activity"]) - (now - 61)).abs() <= 1);
+        assert!((timestamp(&timing["expiry_date"]) - (now + 30)).abs() <= 1);
+
+        // last_activity is stored in the session, the expiry is only the one
+        // of the stored record, a loaded session reports the inactivity expiry
+        let response = router
+            .oneshot(
+                Request::get("/test_hooks/session")
+                    .header(COOKIE, session_cookie.stripped().to_string())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response_timing(response).await["last_activity"],
+            timing["last_activity"]
+        );
+    }
+}
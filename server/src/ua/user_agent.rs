@@ -5,6 +5,11 @@ use axum::{
 };
 use uaparser::{Parser, UserAgentParser};
 
+use crate::state::AppState;
+
+// user_agent_short of credentials registered without `User-Agent` header
+pub const UNKNOWN_DEVICE: &str = "Unknown device";
+
 pub fn build_parser() -> uaparser::UserAgentParser {
     UserAgentParser::builder()
         .with_unicode_support(false)
@@ -12,7 +17,9 @@ pub fn build_parser() -> uaparser::UserAgentParser {
         .expect("Parser creation failed")
 }
 
-pub struct ExtractUserAgent(pub String);
+// None if the header is missing and REQUIRE_USER_AGENT=false (privacy
+// browsers, api clients), rejected with 400 otherwise (default)
+pub struct ExtractUserAgent(pub Option<String>);
 
 #[async_trait]
 impl<S> FromRequestParts<S> for ExtractUserAgent
@@ -23,28 +30,57 @@ where
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         if let Some(user_agent) = parts.headers.get(USER_AGENT) {
-            Ok(ExtractUserAgent(
+            Ok(ExtractUserAgent(Some(
                 user_agent
                     .to_str()
                     .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid `User-Agent` header"))?
                     .to_string(),
-            ))
-        } else {
+            )))
+        } else if parts
+            .extensions
+            .get::<AppState>()
+            .map_or(true, |app_state| app_state.config.require_user_agent)
+        {
             Err((StatusCode::BAD_REQUEST, "`User-Agent` header is missing"))
+        } else {
+            Ok(ExtractUserAgent(None))
         }
     }
 }
 
-pub fn get_user_agent_string_short(user_agent: &str, parser: &uaparser::UserAgentParser) -> String {
-    let ua = parser.parse(user_agent);
-    let device = [
-        ua.device.brand.unwrap_or(Default::default()),
-        ua.device.family,
-    ]
-    .iter()
-    .filter(|s| !s.is_empty())
-    .map(|s| s.to_string())
-    .collect::<Vec<String>>()
-    .join(" ");
-    [ua.user_agent.family, ua.os.family, device.into()].join(" - ")
+// family names of the parsed user agent, stored with the authenticator
+#[derive(Debug, Clone)]
+pub struct ParsedUserAgent {
+    pub browser: String,
+    pub os: String,
+    // brand and family, e.g. "Apple iPhone"
+    pub device: String,
+}
+
+impl ParsedUserAgent {
+    pub fn parse(user_agent: &str, parser: &uaparser::UserAgentParser) -> Self {
+        let ua = parser.parse(user_agent);
+        let device = [
+            ua.device.brand.unwrap_or(Default::default()),
+            ua.device.family,
+        ]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>()
+        .join(" ");
+        ParsedUserAgent {
+            browser: ua.user_agent.family.into(),
+            os: ua.os.family.into(),
+            device,
+        }
+    }
+}
+
+// e.g. "Safari - iOS - Apple iPhone", UNKNOWN_DEVICE without user agent
+pub fn get_user_agent_string_short(user_agent: Option<&ParsedUserAgent>) -> String {
+    match user_agent {
+        Some(ua) => [ua.browser.as_str(), ua.os.as_str(), ua.device.as_str()].join(" - "),
+        None => UNKNOWN_DEVICE.to_string(),
+    }
 }